@@ -7,8 +7,8 @@ use std::env;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 
-fn deserialize<'a, T: serde::Deserialize<'a>>(data: &'a String) -> Result<T, Error> {
-    let response: T = serde_json::from_str(&data)?;
+fn deserialize<'a, T: serde::Deserialize<'a>>(data: &'a str) -> Result<T, Error> {
+    let response: T = serde_json::from_str(data)?;
     Ok(response)
 }
 
@@ -43,12 +43,12 @@ fn main() -> Result<(), Error> {
     if args.len() < arg_index+1 {return Err(Error::TooFewArgs());}
 
     let mut stream = TcpStream::connect(&cliurl)?;
-    let request = JsonRequest{ method: (&args[arg_index]).to_string(), args: args[arg_index+1..args.len()].iter().cloned().collect()};
-    stream.write(serde_json::to_string(&request)?.as_bytes())?;
+    let request = JsonRequest{ method: args[arg_index].to_string(), args: args[arg_index+1..args.len()].to_vec()};
+    stream.write_all(serde_json::to_string(&request)?.as_bytes())?;
     stream.shutdown(std::net::Shutdown::Write)?;
     let mut data = String::new();
     stream.read_to_string(&mut data)?;
-    if data == "" {return Err(Error::UnexpectedShutdown());}
+    if data.is_empty() {return Err(Error::UnexpectedShutdown());}
     match deserialize::<JsonResponse>(&data) {
         Ok(response) if response.status == 1 => println!("{}", response.message),
         Ok(response) if response.status == 2 => println!("{}", response.message),
@@ -1,9 +1,13 @@
 use crate::{Config, Error, Value, Auth, Client, RpcApi};
 use crate::{hex_encode, hex_decode, json};
 use crate::OP_RETURN;
+use crate::chain_source::ChainSource;
+
+use bitcoin::Transaction;
+use bitcoin::consensus::deserialize;
 
 pub fn get_bitcoin_rpc(config: &Config) -> Result<Client, Error> {
-    let walletless_rpc = Client::new(&config.rpcurl, Auth::UserPass((&config.rpcuser).to_owned(), (&config.rpcpassword).to_owned()))?;
+    let walletless_rpc = Client::new(&config.rpcurl, Auth::UserPass(config.rpcuser.to_owned(), config.rpcpassword.to_owned()))?;
     match walletless_rpc.load_wallet(&config.wallet) {
         Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e))) if e.code == -35 => {
             Ok(()) //-35 == Wallet Already Loaded
@@ -11,10 +15,12 @@ pub fn get_bitcoin_rpc(config: &Config) -> Result<Client, Error> {
         Err(e) => Err(e),
         Ok(_) => Ok(())
     }?;
-    Ok(Client::new(&(config.rpcurl.clone()+"/wallet/"+&config.wallet), Auth::UserPass((&config.rpcuser).to_owned(), (&config.rpcpassword).to_owned()))?)
+    Ok(Client::new(&(config.rpcurl.clone()+"/wallet/"+&config.wallet), Auth::UserPass(config.rpcuser.to_owned(), config.rpcpassword.to_owned()))?)
 }
 
-pub fn send_transaction(rpc: &Client, output_script: String, price: u64) -> Result<String, Error> {
+//Funds and signs the transaction with the wallet and broadcasts it through the
+//chain source.
+pub fn send_transaction(rpc: &Client, chain: &dyn ChainSource, output_script: String, price: u64) -> Result<String, Error> {
     let mut outputs: Value = json!(null);
     outputs["data"] = json!(output_script);
     let inputs: Vec<Value> = vec![];
@@ -32,7 +38,7 @@ pub fn send_transaction(rpc: &Client, output_script: String, price: u64) -> Resu
         &hex_encode((price as i64).to_le_bytes())+
         &raw_tx[(position-1)*2..];
 
-    rpc.call::<Value>("decoderawtransaction", &vec![json!(raw_tx)])?;
+    rpc.call::<Value>("decoderawtransaction", &[json!(raw_tx)])?;
 
     let frt_args: Vec<Value> = vec![json!(raw_tx)];
     let funded_tx: Value = rpc.call("fundrawtransaction", &frt_args)?;
@@ -42,6 +48,6 @@ pub fn send_transaction(rpc: &Client, output_script: String, price: u64) -> Resu
 
     if !signed_tx["complete"].as_bool().unwrap() {return Err(Error::CouldNotSignTransaction())}
 
-    let send_rt_args: Vec<Value> = vec![signed_tx["hex"].clone(), json!(0.10), json!(1)];
-    Ok(rpc.call::<String>("sendrawtransaction", &send_rt_args)?)
+    let signed_tx: Transaction = deserialize(&hex_decode(signed_tx["hex"].as_str().unwrap())?)?;
+    Ok(chain.broadcast(&signed_tx)?.to_string())
 }
@@ -0,0 +1,307 @@
+use crate::{Error, Config, Mempool};
+use crate::chain_source::ChainSource;
+use crate::scanner::{Scanner, ScanProgress};
+use crate::storage::StorageHandle;
+
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::pow::Work;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{read, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+//Every block in a blk file is the network magic, the size of the block and
+//then the block itself.
+const MAGIC_SIZE: usize = 4;
+const RECORD_HEADER_SIZE: u64 = 8;
+const BLOCK_HEADER_SIZE: usize = 80;
+//Bitcoin Core 28 and later obfuscate the block files with the key in
+//blocks/xor.dat, byte i of a file is XORed with byte i % 8 of the key.
+const XOR_KEY_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockLocation {
+    file: u32,
+    //Offset of the block itself, past the magic and size
+    offset: u64,
+    size: u32,
+}
+
+//The best chain read straight from the blk*.dat files of a bitcoind datadir,
+//for importing without one getblock call per block. Only the headers are
+//read up front, blocks are read from their file as they are scanned.
+pub struct BlockFiles {
+    blocks_dir: PathBuf,
+    key: [u8; XOR_KEY_SIZE],
+    //Hash of the block at every height of the best chain
+    chain: Vec<BlockHash>,
+    locations: HashMap<BlockHash, BlockLocation>,
+    //The file last read from, blocks of the best chain are mostly in order
+    file: RefCell<Option<(u32, File)>>,
+}
+
+fn file_path(blocks_dir: &Path, file: u32) -> PathBuf {
+    blocks_dir.join(format!("blk{:05}.dat", file))
+}
+
+fn unobfuscate(key: &[u8; XOR_KEY_SIZE], position: u64, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[(position as usize + i) % XOR_KEY_SIZE];
+    }
+}
+
+impl BlockFiles {
+    pub fn open(datadir: &Path) -> Result<BlockFiles, Error> {
+        let blocks_dir = datadir.join("blocks");
+        let key_path = blocks_dir.join("xor.dat");
+        let key: [u8; XOR_KEY_SIZE] = match key_path.exists() {
+            true => read(key_path)?.as_slice().try_into()?,
+            false => [0; XOR_KEY_SIZE]
+        };
+        let mut headers = Vec::new();
+        let mut magic = None;
+        let mut file = 0;
+        while file_path(&blocks_dir, file).exists() {
+            BlockFiles::read_headers(&blocks_dir, &key, file, &mut magic, &mut headers)?;
+            file += 1;
+        }
+        println!("[INFO] Read {} block headers from {} block files", headers.len(), file);
+        let chain = best_chain(&headers);
+        if chain.is_empty() {
+            return Err(Error::ChainSource(format!("No chain from the genesis block in {}, is the node pruned?",
+                blocks_dir.display())));
+        }
+        let locations = headers.iter().map(|(header, location)| (header.block_hash(), *location)).collect();
+        Ok(BlockFiles{blocks_dir, key, chain, locations, file: RefCell::new(None)})
+    }
+
+    //Reads the header of every block in a file. Files are preallocated, so
+    //a magic of zeros or a block running past the end marks the last block.
+    fn read_headers(blocks_dir: &Path, key: &[u8; XOR_KEY_SIZE], file: u32, magic: &mut Option<[u8; MAGIC_SIZE]>,
+            headers: &mut Vec<(Header, BlockLocation)>) -> Result<(), Error> {
+        let path = file_path(blocks_dir, file);
+        let length = path.metadata()?.len();
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut position = 0;
+        while position + RECORD_HEADER_SIZE + BLOCK_HEADER_SIZE as u64 <= length {
+            let mut record = [0u8; RECORD_HEADER_SIZE as usize];
+            reader.read_exact(&mut record)?;
+            unobfuscate(key, position, &mut record);
+            let record_magic: [u8; MAGIC_SIZE] = record[..MAGIC_SIZE].try_into()?;
+            let size = u32::from_le_bytes(record[MAGIC_SIZE..].try_into()?);
+            if record_magic == [0; MAGIC_SIZE] || position + RECORD_HEADER_SIZE + size as u64 > length {break;}
+            match magic {
+                Some(magic) if *magic != record_magic => return Err(Error::ChainSource(format!(
+                    "Block at {} of {} is from another network", position, path.display()))),
+                Some(_) => (),
+                None => *magic = Some(record_magic)
+            }
+            let mut header = [0u8; BLOCK_HEADER_SIZE];
+            reader.read_exact(&mut header)?;
+            unobfuscate(key, position + RECORD_HEADER_SIZE, &mut header);
+            headers.push((deserialize(&header)?, BlockLocation{file, offset: position + RECORD_HEADER_SIZE, size}));
+            reader.seek_relative(size as i64 - BLOCK_HEADER_SIZE as i64)?;
+            position += RECORD_HEADER_SIZE + size as u64;
+        }
+        Ok(())
+    }
+}
+
+//The chain with the most work that goes back to the genesis block, which is
+//the one block whose previous block hash is all zeros. Blocks are stored in
+//the order they were downloaded, so parents can come after their children.
+fn best_chain(headers: &[(Header, BlockLocation)]) -> Vec<BlockHash> {
+    let parents: HashMap<BlockHash, (BlockHash, Work)> = headers.iter()
+        .map(|(header, _)| (header.block_hash(), (header.prev_blockhash, header.work())))
+        .collect();
+    //Total work of every block, None for blocks whose ancestors are missing
+    let mut totals: HashMap<BlockHash, Option<Work>> = HashMap::new();
+    for hash in parents.keys() {
+        let mut path = vec![*hash];
+        let mut total = loop {
+            let hash = *path.last().unwrap();
+            if let Some(total) = totals.get(&hash) {
+                path.pop();
+                break *total;
+            }
+            match parents.get(&hash) {
+                Some((parent, _)) if *parent == BlockHash::all_zeros() => break Some(Work::from_be_bytes([0; 32])),
+                Some((parent, _)) => path.push(*parent),
+                None => {
+                    path.pop();
+                    break None;
+                }
+            }
+        };
+        for hash in path.into_iter().rev() {
+            total = total.map(|total| total + parents[&hash].1);
+            totals.insert(hash, total);
+        }
+    }
+    let mut tip = match totals.iter().filter_map(|(hash, total)| total.map(|total| (total, *hash))).max() {
+        Some((_, tip)) => tip,
+        None => return vec![]
+    };
+    let mut chain = vec![tip];
+    while let Some((parent, _)) = parents.get(&tip).filter(|(parent, _)| *parent != BlockHash::all_zeros()) {
+        tip = *parent;
+        chain.push(tip);
+    }
+    chain.reverse();
+    chain
+}
+
+impl ChainSource for BlockFiles {
+    fn block_count(&self) -> Result<u64, Error> {
+        Ok(self.chain.len() as u64 - 1)
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        self.chain.get(block_height as usize).copied()
+            .ok_or(Error::ChainSource(format!("Block files have no block at height {}", block_height)))
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error> {
+        let location = *self.locations.get(block_hash)
+            .ok_or(Error::ChainSource(format!("Block files have no block {}", block_hash)))?;
+        let mut cached = self.file.borrow_mut();
+        if cached.as_ref().map(|(file, _)| *file) != Some(location.file) {
+            *cached = Some((location.file, File::open(file_path(&self.blocks_dir, location.file))?));
+        }
+        let (_, file) = cached.as_mut().unwrap();
+        let mut block = vec![0u8; location.size as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut block)?;
+        unobfuscate(&self.key, location.offset, &mut block);
+        Ok(deserialize(&block)?)
+    }
+
+    //Fees need the outputs the transaction spends, which are not in the block.
+    fn transaction_fee(&self, _tx: &Transaction) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    fn mempool(&self) -> Result<Vec<Txid>, Error> {
+        Ok(vec![])
+    }
+
+    fn mempool_entry(&self, _txid: &Txid) -> Result<Option<(u64, u64)>, Error> {
+        Ok(None)
+    }
+
+    fn transaction(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(None)
+    }
+}
+
+//Indexes every block of the best chain in the block files that has not been
+//scanned yet. Records imported without a fee keep none.
+pub fn import(config: &Config, storage: &StorageHandle, datadir: &Path, block_height: u64) -> Result<(), Error> {
+    let files = BlockFiles::open(datadir)?;
+    let tip = files.block_count()?;
+    println!("[INFO] Importing blocks {} to {} from {}", block_height, tip, datadir.display());
+    let mut scanner = Scanner::new(config, storage, block_height, Mempool::default(), ScanProgress::default())?;
+    //Blocks are committed in batches like the initial block scan does
+    while scanner.block_height <= tip {
+        scanner.scan_batched(&files, tip)?;
+    }
+    scanner.collect_garbage()?;
+    println!("[INFO] Imported up to block {}", tip);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::BLOCK_HEIGHT;
+    use crate::test_util;
+    use bitcoin::consensus::serialize;
+    use std::fs::{create_dir_all, write};
+
+    const MAGIC: [u8; MAGIC_SIZE] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    //Writes each list of blocks to a blk file of its own, obfuscated with the
+    //key if there is one and padded with zeros like a preallocated file.
+    fn write_block_files(datadir: &Path, key: Option<[u8; XOR_KEY_SIZE]>, files: &[Vec<&Block>]) {
+        let blocks_dir = datadir.join("blocks");
+        create_dir_all(&blocks_dir).unwrap();
+        if let Some(key) = key {write(blocks_dir.join("xor.dat"), key).unwrap();}
+        for (file, blocks) in files.iter().enumerate() {
+            let mut data = Vec::new();
+            for block in blocks {
+                let block = serialize(*block);
+                data.extend_from_slice(&MAGIC);
+                data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+                data.extend_from_slice(&block);
+            }
+            data.extend_from_slice(&[0; 100]);
+            unobfuscate(&key.unwrap_or_default(), 0, &mut data);
+            write(file_path(&blocks_dir, file as u32), data).unwrap();
+        }
+    }
+
+    //The best chain, stored out of order next to a stale fork with less work.
+    fn blocks() -> (Vec<Block>, Vec<Block>) {
+        let best = test_util::chain(&[], 0, 7, 1);
+        let stale = test_util::chain(&best, 3, 5, 2);
+        (best, stale)
+    }
+
+    fn check(files: &BlockFiles, best: &[Block], stale: &[Block]) {
+        assert_eq!(files.block_count().unwrap(), best.len() as u64 - 1);
+        for (block_height, block) in best.iter().enumerate() {
+            assert_eq!(files.block_hash(block_height as u64).unwrap(), block.block_hash());
+            assert_eq!(&files.block(&block.block_hash()).unwrap(), block);
+        }
+        assert!(files.block_hash(best.len() as u64).is_err());
+        //Stale blocks can still be read, they are just not on the best chain
+        assert_eq!(files.block(&stale[4].block_hash()).unwrap(), stale[4]);
+    }
+
+    #[test]
+    fn best_chain_out_of_order() {
+        let (best, stale) = blocks();
+        for key in [None, Some([0x5a, 1, 2, 3, 4, 5, 6, 0xff])] {
+            let datadir = test_util::temp_dir();
+            write_block_files(&datadir, key, &[
+                vec![&best[0], &best[2], &best[1], &stale[3]],
+                vec![&best[4], &stale[4], &best[3], &best[5], &best[6]],
+            ]);
+            check(&BlockFiles::open(&datadir).unwrap(), &best, &stale);
+        }
+    }
+
+    #[test]
+    fn missing_genesis() {
+        let (best, _) = blocks();
+        let datadir = test_util::temp_dir();
+        write_block_files(&datadir, None, &[vec![&best[1], &best[2]]]);
+        assert!(BlockFiles::open(&datadir).is_err());
+    }
+
+    #[test]
+    fn import_blocks() {
+        let (best, stale) = blocks();
+        let datadir = test_util::temp_dir();
+        write_block_files(&datadir, Some([7; XOR_KEY_SIZE]), &[best.iter().chain(&stale[3..]).collect()]);
+        let config = Config{scanbatch: 2, ..test_util::config()};
+        let storage = StorageHandle::memory().unwrap();
+        import(&config, &storage, &datadir, 1).unwrap();
+        let index = storage.open().unwrap();
+        assert_eq!(index.setting(BLOCK_HEIGHT).unwrap(), Some(best.len().to_string()));
+        for (block_height, block) in best.iter().enumerate().skip(1) {
+            assert_eq!(index.block_hash(block_height as u64).unwrap(), Some(block.block_hash().to_string()));
+        }
+        for block in &stale[3..] {
+            test_util::assert_not_indexed(index.as_ref(), block);
+        }
+        let record = index.hash_by_txid(&best[4].txdata[0].txid().to_string()).unwrap().unwrap();
+        assert_eq!(record.block_height, 4);
+        assert_eq!(record.location.unwrap().fee, None);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::Error;
+use crate::merkle::{self, MerkleHash, PageProof};
+use crate::{PRICE_PER_BYTE, FLAG_HASH, FLAG_SHA256_HASH};
+
+use bitcoin::hashes::{sha256, Hash};
+use serde::{Serialize, Deserialize};
+use sha1::{Sha1, Digest};
+
+pub const BOOK_HASH_SIZE: usize = 20;
+pub const SHA256_BOOK_HASH_SIZE: usize = 32;
+
+//The algorithm a published book hash was made with. SHA-1 covers both the
+//SHA-1 hash of a book and its Merkle root as they are published the same way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_str(algorithm: &str) -> HashAlgorithm {
+        match algorithm {
+            "sha256" => HashAlgorithm::Sha256,
+            _ => HashAlgorithm::Sha1,
+        }
+    }
+
+    pub fn from_size(size: usize) -> Option<HashAlgorithm> {
+        match size {
+            BOOK_HASH_SIZE => Some(HashAlgorithm::Sha1),
+            SHA256_BOOK_HASH_SIZE => Some(HashAlgorithm::Sha256),
+            _ => None
+        }
+    }
+
+    //The flag hashes of this algorithm are published under.
+    pub fn flag(&self) -> u8 {
+        match self {
+            HashAlgorithm::Sha1 => FLAG_HASH,
+            HashAlgorithm::Sha256 => FLAG_SHA256_HASH,
+        }
+    }
+}
+
+//Pages with fields besides price and data are rejected rather than dropping
+//what the canonical encoding would leave out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Page {
+    pub price: u64,
+    pub data: String,
+}
+
+impl Page {
+    pub fn from_json(data: &[u8]) -> Result<Page, Error> {
+        let page: Page = serde_json::from_slice(data)?;
+        if !page.is_priced() {
+            return Err(Error::InvalidBook(format!("Page is not priced at {} sat per byte", PRICE_PER_BYTE)));
+        }
+        Ok(page)
+    }
+
+    //Canonical encoding of a single page, the same bytes it takes up in the
+    //canonical encoding of its book.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    //The price of a Page is PRICE_PER_BYTE for every byte of its data.
+    pub fn is_priced(&self) -> bool {
+        self.price == self.data.len() as u64 * PRICE_PER_BYTE
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Book {
+    pub pages: Vec<Page>,
+}
+
+impl Book {
+    pub fn from_json(data: &[u8]) -> Result<Book, Error> {
+        let book: Book = serde_json::from_slice(data)?;
+        if let Some(index) = book.pages.iter().position(|page| !page.is_priced()) {
+            return Err(Error::InvalidBook(format!("Page {} is not priced at {} sat per byte", index, PRICE_PER_BYTE)));
+        }
+        Ok(book)
+    }
+
+    //The canonical encoding is the JSON array of pages with no whitespace and
+    //the fields of every page in the order price, data. This is exactly what
+    //a book hash commits to.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.pages).unwrap()
+    }
+
+    //SHA-1 of the canonical encoding, the 20 bytes published by FLAG_HASH
+    //transactions.
+    pub fn hash(&self) -> [u8; BOOK_HASH_SIZE] {
+        Sha1::digest(self.encode()).into()
+    }
+
+    //SHA-256 of the canonical encoding, the 32 bytes published by
+    //FLAG_SHA256_HASH transactions.
+    pub fn sha256(&self) -> [u8; SHA256_BOOK_HASH_SIZE] {
+        sha256::Hash::hash(&self.encode()).to_byte_array()
+    }
+
+    //A book hash can instead commit to the root of a Merkle tree over the
+    //pages, letting a single page be served and verified with a PageProof.
+    pub fn merkle_root(&self) -> Result<[u8; BOOK_HASH_SIZE], Error> {
+        merkle::root(self.leaves())
+    }
+
+    pub fn page_proof(&self, index: usize) -> Result<PageProof, Error> {
+        merkle::proof(self.leaves(), index)
+    }
+
+    fn leaves(&self) -> Vec<MerkleHash> {
+        self.pages.iter().map(|page| merkle::page_digest(&page.encode())).collect()
+    }
+
+    //Every commitment a published hash may be for this book.
+    pub fn commitments(&self) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(vec![self.hash().to_vec(), self.merkle_root()?.to_vec(), self.sha256().to_vec()])
+    }
+
+    pub fn price(&self) -> u64 {
+        self.pages.iter().map(|page| page.price).sum()
+    }
+
+    //Size of the canonical encoding in bytes.
+    pub fn size(&self) -> u64 {
+        self.encode().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_encode;
+
+    //The Book of the README.
+    const CANONICAL: &str = r#"[{"price":10,"data":"1234567890"},{"price":20,"data":"12345678901112131415"}]"#;
+
+    fn book() -> Book {
+        Book{pages: vec![
+            Page{price: 10, data: "1234567890".to_string()},
+            Page{price: 20, data: "12345678901112131415".to_string()},
+        ]}
+    }
+
+    #[test]
+    fn known_answer() {
+        let book = book();
+        assert_eq!(book.encode(), CANONICAL.as_bytes());
+        assert_eq!(book.size(), CANONICAL.len() as u64);
+        assert_eq!(hex_encode(book.hash()), "522167574076301ff8c443f8635f581d11578cda");
+        assert_eq!(hex_encode(book.sha256()), "ad00c95e2ee7ec08b3c33f209058537345165854d1b6b18a7375b363416092cf");
+        assert_eq!(Book::from_json(CANONICAL.as_bytes()).unwrap(), book);
+    }
+
+    //Whitespace and the order of fields are normalised away, anything the
+    //canonical encoding has no place for is rejected.
+    #[test]
+    fn non_canonical() {
+        let reordered = r#"[ {"data": "1234567890", "price": 10},
+            {"data": "12345678901112131415", "price": 20} ]"#;
+        let normalised = Book::from_json(reordered.as_bytes()).unwrap();
+        assert_eq!(normalised.encode(), CANONICAL.as_bytes());
+        assert_eq!(normalised.hash(), book().hash());
+
+        let extra = r#"[{"price":10,"data":"1234567890","title":"x"},{"price":20,"data":"12345678901112131415"}]"#;
+        assert!(Book::from_json(extra.as_bytes()).is_err());
+        assert!(Page::from_json(br#"{"price":10,"data":"1234567890","title":"x"}"#).is_err());
+        assert!(Book::from_json(br#"{"pages":[]}"#).is_err());
+        assert!(Book::from_json(br#"[{"price":10}]"#).is_err());
+    }
+}
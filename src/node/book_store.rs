@@ -0,0 +1,145 @@
+use crate::{Error, Book};
+use crate::storage::Storage;
+use crate::database::HashStatus;
+use crate::book::HashAlgorithm;
+use crate::{hex_encode, hex_decode};
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_dir, remove_file, rename, write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+enum Books {
+    //One file per book named by its hash
+    Files(PathBuf),
+    //Canonical encodings by hash, shared by every clone
+    Memory(Arc<Mutex<BTreeMap<String, Vec<u8>>>>),
+}
+
+//Resolved books, each stored under its hash as the canonical encoding of the
+//book. Nodes keep them under datadir/books unless they run in memory.
+#[derive(Clone)]
+pub struct BookStore {
+    books: Books,
+}
+
+//Only well formed hashes ever become keys or file names.
+fn book_key(hash: &str) -> Result<String, Error> {
+    match hex_decode(hash) {
+        Ok(bytes) if HashAlgorithm::from_size(bytes.len()).is_some() => Ok(hex_encode(bytes)),
+        _ => Err(Error::InvalidBook(format!("Hash({}) is not a 20 or 32 byte Hex String", hash)))
+    }
+}
+
+impl BookStore {
+    pub fn files(datadir: &Path) -> Result<BookStore, Error> {
+        let path = datadir.join("books");
+        create_dir_all(&path)?;
+        Ok(BookStore{books: Books::Files(path)})
+    }
+
+    pub fn memory() -> BookStore {
+        BookStore{books: Books::Memory(Arc::new(Mutex::new(BTreeMap::new())))}
+    }
+
+    pub fn insert(&self, hash: &str, book: &Book) -> Result<(), Error> {
+        let key = book_key(hash)?;
+        if !book.commitments()?.iter().any(|commitment| hex_encode(commitment) == key) {
+            return Err(Error::InvalidBook(format!("Book does not match Hash({})", hash)));
+        }
+        match &self.books {
+            Books::Files(path) => {
+                //Write to a temporary file first so a book is never half stored
+                let path = path.join(key);
+                let temporary_path = path.with_extension("tmp");
+                write(&temporary_path, book.encode())?;
+                rename(temporary_path, path)?;
+            },
+            Books::Memory(books) => {books.lock().unwrap().insert(key, book.encode());}
+        }
+        Ok(())
+    }
+
+    //Stores the book under whichever of its commitments has been published and
+    //resolves the status of that hash.
+    pub fn add(&self, storage: &dyn Storage, book: &Book) -> Result<Option<(String, HashStatus)>, Error> {
+        for commitment in book.commitments()? {
+            let hash = hex_encode(commitment);
+            if let Some(status) = storage.resolve_hash(&hash, book.size())? {
+                self.insert(&hash, book)?;
+                return Ok(Some((hash, status)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get(&self, hash: &str) -> Result<Option<Book>, Error> {
+        let key = book_key(hash)?;
+        let encoded = match &self.books {
+            Books::Files(path) => match path.join(key) {
+                path if path.exists() => read(path)?,
+                _ => return Ok(None)
+            },
+            Books::Memory(books) => match books.lock().unwrap().get(&key) {
+                Some(encoded) => encoded.clone(),
+                None => return Ok(None)
+            }
+        };
+        Ok(Some(Book::from_json(&encoded)?))
+    }
+
+    pub fn has(&self, hash: &str) -> Result<bool, Error> {
+        let key = book_key(hash)?;
+        match &self.books {
+            Books::Files(path) => Ok(path.join(key).exists()),
+            Books::Memory(books) => Ok(books.lock().unwrap().contains_key(&key))
+        }
+    }
+
+    pub fn remove(&self, hash: &str) -> Result<(), Error> {
+        let key = book_key(hash)?;
+        match &self.books {
+            Books::Files(path) => {
+                let path = path.join(key);
+                if path.exists() {remove_file(path)?;}
+            },
+            Books::Memory(books) => {books.lock().unwrap().remove(&key);}
+        }
+        Ok(())
+    }
+
+    //Returns the hash and size in bytes of every stored book.
+    pub fn list(&self) -> Result<Vec<(String, u64)>, Error> {
+        let mut books = Vec::new();
+        match &self.books {
+            Books::Files(path) => for entry in read_dir(path)? {
+                let entry = entry?;
+                let hash = entry.file_name().to_string_lossy().to_string();
+                if book_key(&hash).is_err() {continue;}
+                books.push((hash, entry.metadata()?.len()));
+            },
+            Books::Memory(memory) => books.extend(memory.lock().unwrap().iter()
+                .map(|(hash, encoded)| (hash.clone(), encoded.len() as u64)))
+        }
+        books.sort();
+        Ok(books)
+    }
+
+    pub fn size(&self) -> Result<u64, Error> {
+        Ok(self.list()?.iter().map(|(_, size)| size).sum())
+    }
+
+    //Removes books whose hash is no longer indexed, returns how many were removed.
+    pub fn collect_garbage(&self, storage: &dyn Storage) -> Result<usize, Error> {
+        let mut removed = 0;
+        for (hash, _) in self.list()? {
+            if storage.hash(&hash)?.is_none() {
+                println!("[INFO] Removing book {} as its hash is no longer indexed", hash);
+                self.remove(&hash)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
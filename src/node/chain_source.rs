@@ -0,0 +1,104 @@
+use crate::{Error, Config, Client, RpcApi, json, hex_encode};
+use crate::get_bitcoin_rpc;
+use crate::fixture_chain::FixtureChain;
+use crate::esplora::Esplora;
+
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+use bitcoin::consensus::serialize;
+use std::str::FromStr;
+
+//Where the node reads the chain and the mempool from and broadcasts to.
+//Bitcoin Core RPC is the default, an Esplora API can be used instead and a
+//fixture file stands in for either to scan canned blocks.
+pub trait ChainSource: Send {
+    //Height of the tip of the best chain
+    fn block_count(&self) -> Result<u64, Error>;
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error>;
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error>;
+
+    //Txids of every transaction in the mempool
+    fn mempool(&self) -> Result<Vec<Txid>, Error>;
+    //(fee, vsize) of a mempool transaction, None once it left the mempool
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<(u64, u64)>, Error>;
+    //None if the source does not know the transaction
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error>;
+    //Whether the mempool can be synced from this source, sources that would
+    //need a request per transaction leave the mempool untracked
+    fn tracks_mempool(&self) -> bool {
+        true
+    }
+
+    //The fee a transaction paid, its inputs less its outputs. None if the
+    //source cannot tell, as when a transaction it spends is unknown.
+    fn transaction_fee(&self, tx: &Transaction) -> Result<Option<u64>, Error> {
+        let mut input_value = 0u64;
+        for input in &tx.input {
+            let previous_output = input.previous_output;
+            let value = self.transaction(&previous_output.txid)?
+                .and_then(|spent| spent.output.get(previous_output.vout as usize).map(|output| output.value));
+            match value {
+                Some(value) => input_value += value,
+                None => return Ok(None)
+            }
+        }
+        Ok(input_value.checked_sub(tx.output.iter().map(|output| output.value).sum()))
+    }
+
+    fn broadcast(&self, _tx: &Transaction) -> Result<Txid, Error> {
+        Err(Error::ChainSource("Broadcasting is not supported".to_string()))
+    }
+
+    //Blocks until a new block arrives or the timeout in milliseconds runs
+    //out, sources that cannot wait return an error and are polled instead.
+    fn wait_for_block(&self, _timeout: u64) -> Result<(), Error> {
+        Err(Error::ChainSource("Waiting for blocks is not supported".to_string()))
+    }
+}
+
+impl ChainSource for Client {
+    fn block_count(&self) -> Result<u64, Error> {
+        Ok(self.get_block_count()?)
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        Ok(self.get_block_hash(block_height)?)
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error> {
+        Ok(self.get_block(block_hash)?)
+    }
+
+    fn mempool(&self) -> Result<Vec<Txid>, Error> {
+        Ok(self.get_raw_mempool()?)
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<(u64, u64)>, Error> {
+        match self.get_mempool_entry(txid) {
+            Ok(entry) => Ok(Some((entry.fees.base.to_sat(), entry.vsize))),
+            Err(_) => Ok(None)
+        }
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(self.get_raw_transaction(txid, None).ok())
+    }
+
+    //The TBPUB output burns its price, so up to 1 BTC may be burnt.
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid: String = self.call("sendrawtransaction", &[json!(hex_encode(serialize(tx))), json!(0.10), json!(1)])?;
+        Ok(Txid::from_str(&txid)?)
+    }
+
+    fn wait_for_block(&self, timeout: u64) -> Result<(), Error> {
+        self.wait_for_new_block(timeout)?;
+        Ok(())
+    }
+}
+
+pub fn open(config: &Config) -> Result<Box<dyn ChainSource>, Error> {
+    match (&config.chainfixture, &config.esplora) {
+        (Some(path), _) => Ok(Box::new(FixtureChain::new(path.clone()))),
+        (None, Some(url)) => Ok(Box::new(Esplora::new(url))),
+        (None, None) => Ok(Box::new(get_bitcoin_rpc(config)?))
+    }
+}
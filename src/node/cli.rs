@@ -1,6 +1,13 @@
-use crate::{Error, Value, Config, SettingsDB, RpcApi, TBPubTransaction};
+use crate::{Error, Value, Config, Mempool, Book, Page};
+use crate::storage::{StorageHandle, BLOCK_HEIGHT};
+use crate::chain_source::{self, ChainSource};
+use crate::scanner::ScanProgress;
+use crate::merkle::PageProof;
+use crate::did_method::{DIDMethod, DHTMethod};
+use crate::tbpub_transaction::{did_from_bytes, Payload};
 use crate::{json, json_to_string, hex_encode, hex_decode, get_bitcoin_rpc, send_transaction};
-use crate::{TBPUB, FLAG_DID, FLAG_HASH};
+use crate::{FLAG_DID, FLAG_METHOD_DID, BOOK_HASH_SIZE};
+use crate::book::HashAlgorithm;
 
 use serde::{Serialize, Deserialize};
 
@@ -13,7 +20,20 @@ enum ArgumentType {
 pub enum RequestMethod {
     BroadcastDID,
     BroadcastHash,
+    GetBook,
+    GetDID,
+    GetHash,
+    GetLosers,
+    GetPageProof,
+    GetShareStats,
+    GetTBPub,
     GetInfo,
+    GetMempoolTBPub,
+    HashBook,
+    HasBook,
+    ListBooks,
+    SubmitBook,
+    VerifyPageProof,
     Help
 }
 
@@ -22,7 +42,20 @@ impl RequestMethod {
         match method {
             "broadcastdid" => Some(RequestMethod::BroadcastDID),
             "broadcasthash" => Some(RequestMethod::BroadcastHash),
+            "getbook" => Some(RequestMethod::GetBook),
+            "getdid" => Some(RequestMethod::GetDID),
+            "gethash" => Some(RequestMethod::GetHash),
+            "getlosers" => Some(RequestMethod::GetLosers),
+            "getpageproof" => Some(RequestMethod::GetPageProof),
+            "getsharestats" => Some(RequestMethod::GetShareStats),
+            "gettbpub" => Some(RequestMethod::GetTBPub),
             "getinfo" => Some(RequestMethod::GetInfo),
+            "getmempooltbpub" => Some(RequestMethod::GetMempoolTBPub),
+            "hashbook" => Some(RequestMethod::HashBook),
+            "hasbook" => Some(RequestMethod::HasBook),
+            "listbooks" => Some(RequestMethod::ListBooks),
+            "submitbook" => Some(RequestMethod::SubmitBook),
+            "verifypageproof" => Some(RequestMethod::VerifyPageProof),
             "help" => Some(RequestMethod::Help),
             "?" => Some(RequestMethod::Help),
             _ => None
@@ -41,13 +74,33 @@ impl RequestMethod {
                 ("price", ArgumentType::Number),
                 ("check_mempool", ArgumentType::Bool)
             ],
+            RequestMethod::GetBook => vec![("hash", ArgumentType::String)],
+            RequestMethod::GetDID => vec![("did", ArgumentType::String)],
+            RequestMethod::GetHash => vec![("hash", ArgumentType::String)],
+            RequestMethod::GetTBPub => vec![("txid", ArgumentType::String)],
+            RequestMethod::GetLosers => vec![("block_height", ArgumentType::Number)],
+            RequestMethod::GetPageProof => vec![
+                ("book", ArgumentType::String),
+                ("index", ArgumentType::Number)
+            ],
             RequestMethod::GetInfo => vec![],
+            RequestMethod::GetShareStats => vec![],
+            RequestMethod::GetMempoolTBPub => vec![],
+            RequestMethod::HashBook => vec![("book", ArgumentType::String)],
+            RequestMethod::HasBook => vec![("hash", ArgumentType::String)],
+            RequestMethod::ListBooks => vec![],
+            RequestMethod::SubmitBook => vec![("book", ArgumentType::String)],
+            RequestMethod::VerifyPageProof => vec![
+                ("root", ArgumentType::String),
+                ("page", ArgumentType::String),
+                ("proof", ArgumentType::String)
+            ],
             RequestMethod::Help => vec![("method", ArgumentType::String)],
         }
     }
 
     fn help(&self) -> JsonResponse {
-        JsonResponse::help(format!("Help Message"))
+        JsonResponse::help("Help Message".to_string())
     }
 ////        match *self {
 ////            RequestMethod::AddPage => JsonResponse::help("
@@ -200,44 +253,39 @@ impl JsonRequest {
         Ok((request_method, result))
     }
 
-    pub fn handel_request(&self, config: &Config) -> Result<JsonResponse, Error> {
+    pub fn handel_request(&self, config: &Config, storage: &StorageHandle, mempool: &Mempool, progress: &ScanProgress) -> Result<JsonResponse, Error> {
         let (method, args) = match self.verify_request() {
             Ok(value) => value,
             Err(response) => return Ok(response)
         };
-        let rpc = get_bitcoin_rpc(&config)?;
 
         match method {
             RequestMethod::BroadcastHash => {
                 let price = args["price"].as_u64().unwrap();
                 let hash = args["hash"].as_str().unwrap();
 
-                match hex_decode(&hash) {
+                //20 byte hashes are SHA-1 or Merkle roots, 32 byte hashes SHA-256
+                let algorithm = match hex_decode(hash) {
                     Err(_) => return Ok(JsonResponse::error(format!(
                                 "Argument({}) is not a valid Hex String", "hash"))),
-                    Ok(bytes) => {
-                        if bytes.len() != 20 {return Ok(JsonResponse::error(format!(
-                                "Argument({}) must be 20 bytes long", "hash")))}
+                    Ok(bytes) => match HashAlgorithm::from_size(bytes.len()) {
+                        Some(algorithm) => algorithm,
+                        None => return Ok(JsonResponse::error(format!(
+                                "Argument({}) must be 20 or 32 bytes long", "hash")))
                     }
-                }
-
-                let txids = rpc.get_raw_mempool()?;
-                for txid in txids {
-                    let tx = match rpc.get_raw_transaction(&txid, None) {
-                        Ok(tx) => tx,
-                        Err(_) => continue
-                    };
-
-                    if TBPubTransaction::from_transaction(&tx).is_none() {continue;}
+                };
 
-                    return Ok(JsonResponse::error(format!("TBPUB Transaction found in mempool with txid({})", txid)));
+                if args["check_mempool"].as_bool().unwrap() {
+                    if let Some(response) = check_mempool(mempool, price) {return Ok(response);}
                 }
 
-                let output_script = format!("{}{}{}", TBPUB, hex_encode([FLAG_HASH]), hex_encode(hash));
-                let txid = match send_transaction(&rpc, output_script, price) {
+                let output_script = Payload::hex(algorithm.flag(), &hex_decode(hash)?);
+                let chain = chain_source::open(config)?;
+                let txid = match send_transaction(&get_bitcoin_rpc(config)?, chain.as_ref(), output_script, price) {
                     Err(e) => return Ok(JsonResponse::error(e.to_string())),
                     Ok(val) => val
                 };
+                track_broadcast(chain.as_ref(), mempool, &txid);
                 let mut result: Value = json!(null);
                 result["txid"] = json!(txid);
                 Ok(JsonResponse::success(json_to_string(&result)?))
@@ -245,26 +293,204 @@ impl JsonRequest {
             RequestMethod::BroadcastDID => {
                 let price = args["price"].as_u64().unwrap();
                 let did = args["did"].as_str().unwrap();
-                //TODO: check if valid did
-                let output_script = format!("{}{}{}", TBPUB, hex_encode([FLAG_DID]), hex_encode(did.as_bytes()));
-                let txid = match send_transaction(&rpc, output_script, price) {
+                let method = match did_from_bytes(did.as_bytes()) {
+                    Some(method) => method,
+                    None => return Ok(JsonResponse::error(format!(
+                        "Argument({}) is not a valid did:dht, did:key or did:web identifier", "did")))
+                };
+                if args["check_mempool"].as_bool().unwrap() {
+                    if let Some(response) = check_mempool(mempool, price) {return Ok(response);}
+                }
+
+                //did:dht keeps using FLAG_DID so older Root Nodes still index it
+                let output_script = match method.id() == DHTMethod.id() {
+                    true => Payload::hex(FLAG_DID, did.as_bytes()),
+                    false => Payload::hex(FLAG_METHOD_DID, &[&[method.id()], did.as_bytes()].concat())
+                };
+                let chain = chain_source::open(config)?;
+                let txid = match send_transaction(&get_bitcoin_rpc(config)?, chain.as_ref(), output_script, price) {
                     Err(e) => return Ok(JsonResponse::error(e.to_string())),
                     Ok(val) => val
                 };
+                track_broadcast(chain.as_ref(), mempool, &txid);
                 let mut result: Value = json!(null);
                 result["txid"] = json!(txid);
                 Ok(JsonResponse::success(json_to_string(&result)?))
             },
+            RequestMethod::GetHash => {
+                let hash = args["hash"].as_str().unwrap().to_lowercase();
+                let record = match storage.open()?.hash(&hash)? {
+                    Some(record) => record,
+                    None => return Ok(JsonResponse::error(format!("Hash({}) has not been published", hash)))
+                };
+                Ok(JsonResponse::success(json_to_string(&record.to_json())?))
+            },
+            RequestMethod::GetDID => {
+                let did = args["did"].as_str().unwrap();
+                match storage.open()?.did(&hex_encode(did))? {
+                    Some(record) => Ok(JsonResponse::success(json_to_string(&record.to_json())?)),
+                    None => Ok(JsonResponse::error(format!("DID({}) has not been published", did)))
+                }
+            },
+            //Looks up the record a TBPUB Transaction published by its txid
+            RequestMethod::GetTBPub => {
+                let txid = args["txid"].as_str().unwrap().to_lowercase();
+                if hex_decode(&txid).map(|bytes| bytes.len()) != Ok(32) {return Ok(JsonResponse::error(format!(
+                        "Argument({}) must be a 32 byte Hex String", "txid")))}
+                let mut result: Value = json!(null);
+                let storage = storage.open()?;
+                if let Some(record) = storage.hash_by_txid(&txid)? {
+                    result["hash"] = record.to_json();
+                } else if let Some(record) = storage.did_by_txid(&txid)? {
+                    result["did"] = record.to_json();
+                } else {
+                    return Ok(JsonResponse::error(format!("Txid({}) did not publish an indexed record", txid)));
+                }
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::HashBook => {
+                let book = match Book::from_json(args["book"].as_str().unwrap().as_bytes()) {
+                    Ok(book) => book,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let mut result: Value = json!(null);
+                result["hash"] = json!(hex_encode(book.hash()));
+                result["merkle_root"] = json!(hex_encode(book.merkle_root()?));
+                result["sha256"] = json!(hex_encode(book.sha256()));
+                result["price"] = json!(book.price());
+                result["size"] = json!(book.size());
+                result["pages"] = json!(book.pages.len());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::SubmitBook => {
+                let book = match Book::from_json(args["book"].as_str().unwrap().as_bytes()) {
+                    Ok(book) => book,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let (hash, status) = match storage.books()?.add(storage.open()?.as_ref(), &book)? {
+                    Some(resolved) => resolved,
+                    None => return Ok(JsonResponse::error(format!("Hash({}) of the book has not been published", hex_encode(book.hash()))))
+                };
+                let mut result: Value = json!(null);
+                result["hash"] = json!(hash);
+                result["size"] = json!(book.size());
+                result["status"] = json!(status.as_str());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::GetPageProof => {
+                let book = match Book::from_json(args["book"].as_str().unwrap().as_bytes()) {
+                    Ok(book) => book,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let index = args["index"].as_u64().unwrap() as usize;
+                let proof = match book.page_proof(index) {
+                    Ok(proof) => proof,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let mut result: Value = json!(null);
+                result["root"] = json!(hex_encode(book.merkle_root()?));
+                result["page"] = json!(book.pages[index]);
+                result["proof"] = json!(proof);
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::VerifyPageProof => {
+                let root: [u8; BOOK_HASH_SIZE] = match hex_decode(args["root"].as_str().unwrap()).map(|root| root.try_into()) {
+                    Ok(Ok(root)) => root,
+                    _ => return Ok(JsonResponse::error(format!(
+                                "Argument({}) must be a {} byte Hex String", "root", BOOK_HASH_SIZE)))
+                };
+                let page = match Page::from_json(args["page"].as_str().unwrap().as_bytes()) {
+                    Ok(page) => page,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let proof: PageProof = match serde_json::from_str(args["proof"].as_str().unwrap()) {
+                    Ok(proof) => proof,
+                    Err(e) => return Ok(JsonResponse::error(e.to_string()))
+                };
+                let valid = proof.verify(&root, &page.encode()).unwrap_or(false);
+                Ok(JsonResponse::success(json_to_string(&json!(valid))?))
+            },
+            RequestMethod::GetBook => {
+                let hash = args["hash"].as_str().unwrap();
+                match storage.books()?.get(hash) {
+                    Ok(Some(book)) => Ok(JsonResponse::success(json_to_string(&book)?)),
+                    Ok(None) => Ok(JsonResponse::error(format!("Book({}) is not stored", hash))),
+                    Err(e) => Ok(JsonResponse::error(e.to_string()))
+                }
+            },
+            RequestMethod::HasBook => {
+                match storage.books()?.has(args["hash"].as_str().unwrap()) {
+                    Ok(has_book) => Ok(JsonResponse::success(json_to_string(&json!(has_book))?)),
+                    Err(e) => Ok(JsonResponse::error(e.to_string()))
+                }
+            },
+            RequestMethod::ListBooks => {
+                let books = storage.books()?.list()?;
+                let mut result: Value = json!(null);
+                result["count"] = json!(books.len());
+                result["size"] = json!(books.iter().map(|(_, size)| size).sum::<u64>());
+                result["books"] = json!(books.iter().map(|(hash, size)| {
+                    let mut book: Value = json!(null);
+                    book["hash"] = json!(hash);
+                    book["size"] = json!(size);
+                    book
+                }).collect::<Vec<Value>>());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::GetShareStats => {
+                let stats = storage.open()?.share_stats()?;
+                let mut result: Value = json!(null);
+                result["books_shared"] = json!(stats.iter().map(|(_, books)| books).sum::<u64>());
+                result["peers"] = json!(stats.iter().map(|(did, books)| {
+                    let mut peer: Value = json!(null);
+                    peer["did"] = json!(String::from_utf8_lossy(&hex_decode(did).unwrap_or_default()));
+                    peer["books_shared"] = json!(books);
+                    peer
+                }).collect::<Vec<Value>>());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
             RequestMethod::GetInfo => {
-                let settings = SettingsDB::new(&config)?;
+                let index = storage.open()?;
                 let mut result: Value = json!(null);
-                result["block_height"] = json!(settings.get("block_height")?.unwrap().parse::<u64>()?);
-                result["initial_block_scan"] = json!(settings.get("initial_block_scan")?.unwrap().parse::<u64>()? != 0);
-                return Ok(JsonResponse::success(json_to_string(&result)?))
+                result["block_height"] = json!(index.setting(BLOCK_HEIGHT)?.unwrap().parse::<u64>()?);
+                result["initial_block_scan"] = json!(index.setting("initial_block_scan")?.unwrap().parse::<u64>()? != 0);
+                result["initial_block_scan_progress"] = progress.to_json();
+                result["books_size"] = json!(storage.books()?.size()?);
+                result["did"] = json!(storage.identity()?.did());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::GetLosers => {
+                let block_height = args["block_height"].as_u64().unwrap();
+                let losers = storage.open()?.losers(block_height)?;
+                let mut result: Value = json!(null);
+                result["block_height"] = json!(block_height);
+                result["winner"] = json!(losers.first().map(|loser| loser.winner.clone()).filter(|winner| !winner.is_empty()));
+                result["losers"] = json!(losers.iter().map(|loser| {
+                    let mut record: Value = json!(null);
+                    record["txid"] = json!(loser.txid);
+                    record["price"] = json!(loser.price);
+                    record["data"] = json!(loser.data);
+                    record["is_hash"] = json!(loser.is_hash);
+                    record["invalid"] = json!(loser.invalid);
+                    record
+                }).collect::<Vec<Value>>());
+                Ok(JsonResponse::success(json_to_string(&result)?))
+            },
+            RequestMethod::GetMempoolTBPub => {
+                let entries = mempool.entries();
+                let mut result: Value = json!(null);
+                result["leader"] = match entries.first() {
+                    Some((txid, entry)) => entry.to_json(txid),
+                    None => json!(null)
+                };
+                result["transactions"] = json!(entries.iter()
+                    .map(|(txid, entry)| entry.to_json(txid))
+                    .collect::<Vec<Value>>());
+                Ok(JsonResponse::success(json_to_string(&result)?))
             },
             RequestMethod::Help => {
                 let method_name = args["method"].as_str().unwrap();
-                let request_method = match RequestMethod::from_string(&method_name) {
+                let request_method = match RequestMethod::from_string(method_name) {
                     Some(r) => r,
                     None => return Ok(JsonResponse::error(format!("Unknown Method({})", method_name)))
                 };
@@ -274,6 +500,30 @@ impl JsonRequest {
     }
 }
 
+//Refuses to broadcast unless the price outbids the current mempool leader,
+//only one TBPUB Transaction per block can be valid.
+fn check_mempool(mempool: &Mempool, price: u64) -> Option<JsonResponse> {
+    match mempool.leader() {
+        Some((txid, leader)) if leader.tx.price >= price => Some(JsonResponse::error(format!(
+            "TBPUB Transaction found in mempool with txid({}) paying {} sats, outbid it or set check_mempool to false",
+            txid, leader.tx.price))),
+        _ => None
+    }
+}
+
+//The transaction is already broadcast, failing to add it to the mempool
+//must not hide its txid. The next mempool sync picks it up anyway.
+fn track_broadcast(chain: &dyn ChainSource, mempool: &Mempool, txid: &str) {
+    if !chain.tracks_mempool() {return;}
+    let result = match txid.parse() {
+        Ok(txid) => mempool.add_txid(chain, &txid),
+        Err(error) => Err(Error::from(error))
+    };
+    if let Err(error) = result {
+        println!("[ERROR] Could not add broadcast transaction {} to the mempool: {}", txid, error);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JsonResponse{
     pub status: u8,
@@ -282,12 +532,156 @@ pub struct JsonResponse{
 
 impl JsonResponse {
     pub fn error(message: String) -> JsonResponse {
-        JsonResponse{status: 0, message: message}
+        JsonResponse{status: 0, message}
     }
     pub fn success(message: String) -> JsonResponse {
-        JsonResponse{status: 1, message: message}
+        JsonResponse{status: 1, message}
     }
     pub fn help(message: String) -> JsonResponse {
-        JsonResponse{status: 2, message: message}
+        JsonResponse{status: 2, message}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Record, BLOCK_HEIGHT};
+    use crate::database::{HashRecord, HashStatus};
+    use crate::test_util;
+
+    fn request(storage: &StorageHandle, method: &str, args: &[&str]) -> JsonResponse {
+        let request = JsonRequest{method: method.to_string(), args: args.iter().map(|arg| arg.to_string()).collect()};
+        request.handel_request(&test_util::config(), storage, &Mempool::default(), &ScanProgress::default()).unwrap()
+    }
+
+    fn publish(storage: &StorageHandle, hash: &str, price: u64) {
+        storage.open().unwrap().add_record(&Record::Hash(HashRecord{hash: hash.to_string(), block_height: 900000,
+            price, status: HashStatus::Pending, algorithm: HashAlgorithm::Sha1, location: None})).unwrap();
+    }
+
+    #[test]
+    fn getinfo() {
+        let storage = StorageHandle::memory().unwrap();
+        let index = storage.open().unwrap();
+        index.set_setting(BLOCK_HEIGHT, "900001").unwrap();
+        index.set_setting("initial_block_scan", "1").unwrap();
+        let response = request(&storage, "getinfo", &[]);
+        assert_eq!(response.status, 1);
+        let info: Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!(info["block_height"], 900001);
+        assert_eq!(info["initial_block_scan"], true);
+        assert_eq!(info["initial_block_scan_progress"], Value::Null);
+        assert_eq!(info["books_size"], 0);
+        assert_eq!(info["did"], storage.identity().unwrap().did());
+    }
+
+    #[test]
+    fn submit_and_get_book() {
+        let storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let encoded = String::from_utf8(book.encode()).unwrap();
+        let hash = hex_encode(book.hash());
+
+        let response = request(&storage, "submitbook", &[&encoded]);
+        assert_eq!(response.status, 0);
+        assert!(response.message.contains("has not been published"));
+
+        publish(&storage, &hash, book.size());
+        let response = request(&storage, "submitbook", &[&encoded]);
+        assert_eq!(response.status, 1);
+        let submitted: Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!(submitted["hash"], hash);
+        assert_eq!(submitted["status"], "valid");
+
+        assert_eq!(request(&storage, "hasbook", &[&hash]).message, "true");
+        let response = request(&storage, "getbook", &[&hash]);
+        assert_eq!(Book::from_json(response.message.as_bytes()).unwrap(), book);
+        let listed: Value = serde_json::from_str(&request(&storage, "listbooks", &[]).message).unwrap();
+        assert_eq!(listed["count"], 1);
+        assert_eq!(listed["size"], book.size());
+        let record: Value = serde_json::from_str(&request(&storage, "gethash", &[&hash]).message).unwrap();
+        assert_eq!(record["status"], "valid");
+    }
+
+    #[test]
+    fn underpaid_book() {
+        let storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        publish(&storage, &hash, book.size() - 1);
+        let response = request(&storage, "submitbook", &[&String::from_utf8(book.encode()).unwrap()]);
+        let submitted: Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!(submitted["status"], "underpaid");
+    }
+
+    //Broadcasting is refused before touching the wallet while a transaction
+    //in the mempool pays at least as much.
+    #[test]
+    fn broadcast_outbid() {
+        let storage = StorageHandle::memory().unwrap();
+        let chain = test_util::MockChain::new(vec![]);
+        let tx = test_util::hash_transaction(1, [1; 20], 20000);
+        chain.add_to_mempool(tx.clone(), 500);
+        let mempool = Mempool::default();
+        mempool.add_transaction(&chain, &tx).unwrap();
+        let broadcast = |method: &str, data: &str, price: &str| {
+            let request = JsonRequest{method: method.to_string(), args: vec![data.to_string(), price.to_string(), "true".to_string()]};
+            request.handel_request(&test_util::config(), &storage, &mempool, &ScanProgress::default()).unwrap()
+        };
+        for price in ["10000", "20000"] {
+            let response = broadcast("broadcasthash", &"00".repeat(20), price);
+            assert_eq!(response.status, 0);
+            assert!(response.message.contains(&tx.txid().to_string()));
+        }
+        let response = broadcast("broadcastdid", &test_util::did(1), "20000");
+        assert!(response.message.contains(&tx.txid().to_string()));
+        //Invalid hashes are refused first
+        assert!(broadcast("broadcasthash", &"00".repeat(21), "30000").message.contains("must be 20 or 32 bytes"));
+    }
+
+    #[test]
+    fn unknown_records() {
+        let storage = StorageHandle::memory().unwrap();
+        assert_eq!(request(&storage, "gethash", &[&"00".repeat(20)]).status, 0);
+        assert_eq!(request(&storage, "getdid", &[&test_util::did(1)]).status, 0);
+        assert_eq!(request(&storage, "gettbpub", &[&"00".repeat(32)]).status, 0);
+        assert_eq!(request(&storage, "nomethod", &[]).status, 0);
+        let stats: Value = serde_json::from_str(&request(&storage, "getsharestats", &[]).message).unwrap();
+        assert_eq!(stats["books_shared"], 0);
+    }
+
+    #[test]
+    fn track_broadcast_errors() {
+        let mempool = Mempool::default();
+        let tx = test_util::hash_transaction(1, [1; 20], crate::MINIMUM_TBPUB_TX_PRICE);
+        let chain = test_util::MockChain::new(test_util::chain(&[], 0, 1, 1));
+        //Neither a malformed txid nor one the chain does not know fail the broadcast
+        track_broadcast(&chain, &mempool, "nonsense");
+        track_broadcast(&chain, &mempool, &tx.txid().to_string());
+        assert!(mempool.entries().is_empty());
+        chain.add_to_mempool(tx.clone(), 1000);
+        track_broadcast(&chain, &mempool, &tx.txid().to_string());
+        assert_eq!(mempool.leader().unwrap().0, tx.txid());
+    }
+
+    #[test]
+    fn page_proofs() {
+        let storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: ["one", "two", "three"].iter().map(|data| Page{price: data.len() as u64, data: data.to_string()}).collect()};
+        let encoded = String::from_utf8(book.encode()).unwrap();
+        let response = request(&storage, "getpageproof", &[&encoded, "2"]);
+        assert_eq!(response.status, 1);
+        let proof: Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!(proof["root"], hex_encode(book.merkle_root().unwrap()));
+        assert_eq!(proof["page"]["data"], "three");
+
+        let verify = |root: &str, page: &Page| request(&storage, "verifypageproof",
+            &[root, &String::from_utf8(page.encode()).unwrap(), &proof["proof"].to_string()]);
+        let root = proof["root"].as_str().unwrap();
+        assert_eq!(verify(root, &book.pages[2]).message, "true");
+        assert_eq!(verify(root, &book.pages[1]).message, "false");
+        assert_eq!(verify(&hex_encode(book.hash()), &book.pages[2]).message, "false");
+        assert_eq!(verify("00", &book.pages[2]).status, 0);
+        assert_eq!(request(&storage, "getpageproof", &[&encoded, "3"]).status, 0);
     }
 }
@@ -1,4 +1,5 @@
 use crate::error::Error;
+use crate::storage::StorageBackend;
 use std::env;
 use std::path::PathBuf;
 use std::fs::{read_to_string, create_dir_all};
@@ -7,10 +8,21 @@ use std::fs::{read_to_string, create_dir_all};
 pub struct Config {
     pub datadir: PathBuf,
     pub cliurl: String,
+    pub peerurl: String,
     pub rpcurl: String,
     pub rpcpassword: String,
     pub rpcuser: String,
     pub wallet: String,
+    pub zmqpubhashblock: Option<String>,
+    pub zmqpubrawtx: Option<String>,
+    pub peers: Vec<(String, String)>,
+    pub didgateway: String,
+    pub storage: StorageBackend,
+    pub chainfixture: Option<PathBuf>,
+    pub importblocks: Option<PathBuf>,
+    pub esplora: Option<String>,
+    pub scanworkers: usize,
+    pub scanbatch: usize,
 }
 
 impl Config {
@@ -20,38 +32,69 @@ impl Config {
             if argp.len() != 2 {return Err(Error::NodeHelpMessage());}
             let mut key: String = argp[0].to_string();
             let value: String = argp[1].to_string();
-            if argp[0].chars().next().unwrap() == '-' {key = key[1..].to_string()};
+            if argp[0].starts_with('-') {key = key[1..].to_string()};
             match key.as_str() {
                 "datadir" => self.datadir = PathBuf::from(value),
                 "cliurl" => self.cliurl = value,
+                "peerurl" => self.peerurl = value,
+                "didgateway" => self.didgateway = value,
+                "storage" => self.storage = StorageBackend::from_str(&value)?,
+                "chainfixture" => self.chainfixture = Some(PathBuf::from(value)),
+                "importblocks" => self.importblocks = Some(PathBuf::from(value)),
+                "esplora" => self.esplora = Some(value),
+                "scanworkers" => self.scanworkers = value.parse::<usize>()?.max(1),
+                "scanbatch" => self.scanbatch = value.parse::<usize>()?.max(1),
+                "addpeer" => {
+                    let (did, url) = value.split_once('@').ok_or(Error::NodeHelpMessage())?;
+                    let peer = (did.to_string(), url.to_string());
+                    if !self.peers.contains(&peer) {self.peers.push(peer);}
+                },
                 "bitcoinrpcurl" => self.rpcurl = value,
                 "bitcoinrpcpassword" => self.rpcpassword = value,
                 "bitcoinrpcuser" => self.rpcuser = value,
                 "wallet" => self.wallet = value,
+                "zmqpubhashblock" => self.zmqpubhashblock = Some(value),
+                "zmqpubrawtx" => self.zmqpubrawtx = Some(value),
                 _ => return Err(Error::UnknownArgument(key)),
             }
         }
         Ok(())
     }
 
-    pub fn new() -> Result<Config, Error> {
-        let mut path = home::home_dir().ok_or(Error::NoHomeDir())?;
-        path.push(".tbpub");
-        let mut config = Config{
-            datadir: path,
+    //The defaults for every option but the datadir.
+    pub fn with_datadir(datadir: PathBuf) -> Config {
+        Config{
+            datadir,
             cliurl: "127.0.0.1:9443".to_string(),
+            peerurl: "0.0.0.0:9444".to_string(),
             rpcurl: "http://localhost:8332".to_string(), 
             rpcpassword: "".to_string(), 
             rpcuser: "".to_string(),
             wallet: "".to_string(),
-        };
+            zmqpubhashblock: None,
+            zmqpubrawtx: None,
+            peers: vec![],
+            didgateway: "https://diddht.tbddev.org".to_string(),
+            storage: StorageBackend::Sqlite,
+            chainfixture: None,
+            importblocks: None,
+            esplora: None,
+            scanworkers: 4,
+            scanbatch: 100,
+        }
+    }
+
+    pub fn new() -> Result<Config, Error> {
+        let mut path = home::home_dir().ok_or(Error::NoHomeDir())?;
+        path.push(".tbpub");
+        let mut config = Config::with_datadir(path);
         create_dir_all(&config.datadir)?;
 
         // Get ENV Args
         let args: Vec<String> = env::args().collect();
 
         //Parse ENV Args
-        config.parse_args(args[1..args.len()].iter().cloned().collect())?;
+        config.parse_args(args[1..args.len()].to_vec())?;
 
         //Parse Config File
         let mut config_file = config.datadir.clone();
@@ -67,8 +110,9 @@ impl Config {
         }
 
         //Parse ENV Args A second time to overwrite config
-        config.parse_args(args[1..args.len()].iter().cloned().collect())?;
-        if config.wallet == "" {return Err(Error::NoWallet());}
+        config.parse_args(args[1..args.len()].to_vec())?;
+        //An offline import never creates transactions
+        if config.wallet.is_empty() && config.importblocks.is_none() {return Err(Error::NoWallet());}
         Ok(config)
     }
 }
@@ -1,97 +1,825 @@
-use crate::{Error, Config};
+use crate::{Error, Value, json};
+use crate::{hex_encode, hex_decode};
+use crate::did_method;
+use crate::book::HashAlgorithm;
+use crate::storage::{Storage, Record, IndexedBlock, BLOCK_HEIGHT};
+
+use sqlite::{OpenFlags, Row, State, Value as SqlValue};
+use std::path::Path;
+use std::rc::Rc;
+
+//Everything the node indexes is kept in one database, the wrappers below can
+//share a connection so what they write can be committed in one transaction.
+pub type Connection = Rc<sqlite::Connection>;
+
+const DATABASE_FILE: &str = "tbpub.db";
+const BUSY_TIMEOUT: usize = 10000;
+
+type Migration = fn(&sqlite::Connection, &Path) -> Result<(), Error>;
+
+//The schema is at version n once the first n migrations have run, new
+//migrations are only ever appended.
+const MIGRATIONS: [Migration; 3] = [create_tables, import_legacy_databases, add_invalid_losers];
+
+pub fn open(datadir: &Path) -> Result<Connection, Error> {
+    let mut database = sqlite::open(datadir.join(DATABASE_FILE))?;
+    database.set_busy_timeout(BUSY_TIMEOUT)?;
+    //Lets the CLI and peer threads read while the scanner writes
+    database.execute("PRAGMA journal_mode = WAL;")?;
+    migrate(&database, datadir)?;
+    Ok(Rc::new(database))
+}
+
+//The number of migrations that have run, read without taking the write lock
+//so opening an up to date database never waits on the scanner.
+fn schema_version(database: &sqlite::Connection) -> Result<usize, Error> {
+    let exists = !query(database, "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version';", &[])?
+        .is_empty();
+    if !exists {return Ok(0);}
+    Ok(query(database, "SELECT MAX(version) AS version FROM schema_version;", &[])?
+        .first()
+        .and_then(|row| row.read::<Option<i64>, _>("version"))
+        .unwrap_or(0) as usize)
+}
+
+fn migrate(database: &sqlite::Connection, datadir: &Path) -> Result<(), Error> {
+    if schema_version(database)? >= MIGRATIONS.len() {return Ok(());}
+    transaction(database, || {
+        database.execute("CREATE TABLE IF NOT EXISTS schema_version (version INT);")?;
+        //Read again under the lock, another thread may have migrated meanwhile
+        let version = schema_version(database)?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            println!("[INFO] Migrating the database to version {}", index + 1);
+            migration(database, datadir)?;
+            execute(database, "INSERT INTO schema_version (version) VALUES(?);", &[int(index as u64 + 1)])?;
+        }
+        Ok(())
+    })
+}
+
+//Runs f in a transaction, committed if f succeeds and rolled back otherwise.
+fn transaction<T>(database: &sqlite::Connection, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    database.execute("BEGIN IMMEDIATE;")?;
+    match f() {
+        Ok(value) => {
+            database.execute("COMMIT;")?;
+            Ok(value)
+        },
+        Err(e) => {
+            let _ = database.execute("ROLLBACK;");
+            Err(e)
+        }
+    }
+}
+
+fn execute(database: &sqlite::Connection, statement: &str, values: &[SqlValue]) -> Result<(), Error> {
+    let mut statement = database.prepare(statement)?;
+    statement.bind(values)?;
+    while statement.next()? != State::Done {}
+    Ok(())
+}
+
+fn query(database: &sqlite::Connection, statement: &str, values: &[SqlValue]) -> Result<Vec<Row>, Error> {
+    Ok(database.prepare(statement)?.into_iter().bind(values)?.collect::<Result<Vec<Row>, _>>()?)
+}
+
+fn int(value: u64) -> SqlValue {
+    SqlValue::Integer(value as i64)
+}
+
+fn text(value: &str) -> SqlValue {
+    SqlValue::String(value.to_string())
+}
+
+fn create_tables(database: &sqlite::Connection, _datadir: &Path) -> Result<(), Error> {
+    Ok(database.execute("
+    CREATE TABLE settings (
+        key TEXT PRIMARY KEY,
+        value TEXT
+    );
+    CREATE TABLE hashes (
+        hash TEXT PRIMARY KEY,
+        block_height INT,
+        price INT,
+        status TEXT DEFAULT 'pending',
+        algorithm TEXT DEFAULT 'sha1',
+        txid TEXT,
+        vout INT,
+        block_hash TEXT,
+        block_time INT,
+        fee INT
+    );
+    CREATE INDEX hashes_txid ON hashes (txid);
+    CREATE TABLE rootdids (
+        did TEXT PRIMARY KEY,
+        block_height INT,
+        price INT,
+        public_key TEXT,
+        txid TEXT,
+        vout INT,
+        block_hash TEXT,
+        block_time INT,
+        fee INT
+    );
+    CREATE INDEX rootdids_txid ON rootdids (txid);
+    CREATE TABLE blocks (
+        block_height INT PRIMARY KEY,
+        hash TEXT
+    );
+    CREATE TABLE losers (
+        txid TEXT PRIMARY KEY,
+        block_height INT,
+        price INT,
+        data TEXT,
+        is_hash INT,
+        winner TEXT
+    );
+    CREATE TABLE shares (
+        hash TEXT,
+        did TEXT,
+        PRIMARY KEY (hash, did)
+    );
+    CREATE TABLE diddocuments (
+        did TEXT PRIMARY KEY,
+        endpoint TEXT,
+        sequence INT,
+        fetched INT,
+        public_key TEXT
+    );")?)
+}
+
+//The files the index was kept in before there was one database, with the
+//columns they had. Everything else older versions kept was never in a file.
+const LEGACY_TABLES: [(&str, &str, &[&str]); 3] = [
+    ("settings.db", "settings", &["key", "value"]),
+    ("hashes.db", "hashes", &["hash", "block_height", "price"]),
+    ("rootdids.db", "rootdids", &["did", "block_height", "price"]),
+];
+
+//Copies the index out of the files older versions kept, which are only read
+//and left in place.
+fn import_legacy_databases(database: &sqlite::Connection, datadir: &Path) -> Result<(), Error> {
+    for (file, table, columns) in LEGACY_TABLES {
+        let path = datadir.join(file);
+        if !path.exists() {continue;}
+        let legacy = sqlite::Connection::open_with_flags(&path, OpenFlags::new().with_read_only())?;
+        let names = columns.join(", ");
+        let insert = format!("INSERT OR IGNORE INTO {} ({}) VALUES({});",
+            table, names, vec!["?"; columns.len()].join(", "));
+        let mut rows = 0;
+        for row in legacy.prepare(format!("SELECT {} FROM {};", names, table))?.into_iter() {
+            execute(database, &insert, &Vec::<SqlValue>::from(row?))?;
+            rows += 1;
+        }
+        println!("[INFO] Imported {} rows from {}", rows, path.display());
+    }
+    backfill_public_keys(database)
+}
+
+//Transactions with more than one TBPUB output are kept with the losers.
+fn add_invalid_losers(database: &sqlite::Connection, _datadir: &Path) -> Result<(), Error> {
+    Ok(database.execute("ALTER TABLE losers ADD COLUMN invalid INT DEFAULT 0;")?)
+}
+
+//DIDs indexed before keys were stored get their key decoded, any that are not
+//valid for a supported method are dropped as they would be now. DIDs whose
+//key is only in their DID document keep no key.
+fn backfill_public_keys(database: &sqlite::Connection) -> Result<(), Error> {
+    let dids: Vec<String> = query(database, "SELECT did FROM rootdids WHERE public_key IS NULL;", &[])?
+        .iter()
+        .map(|row| row.read::<&str, _>("did").to_string())
+        .collect();
+    for did in dids {
+        let decoded = hex_decode(&did).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_default();
+        match did_method::method(&decoded) {
+            Some(method) => if let Some(public_key) = method.public_key(&decoded) {
+                execute(database, "UPDATE rootdids SET public_key = ? WHERE did = ?;",
+                    &[text(&hex_encode(public_key.as_bytes())), text(&did)])?
+            },
+            None => execute(database, "DELETE FROM rootdids WHERE did = ?;", &[text(&did)])?
+        }
+    }
+    Ok(())
+}
 
 pub struct SettingsDB {
-    database: sqlite::Connection,
+    database: Connection,
 }
 
 impl SettingsDB {
-    pub fn new(config: &Config) -> Result<SettingsDB, Error> {
-        let mut database_path = config.datadir.clone();
-        database_path.push("settings.db");
-        let database = sqlite::open(database_path)?;
-        database.execute("
-        CREATE TABLE IF NOT EXISTS settings (
-            key TEXT PRIMARY KEY,
-            value TEXT 
-        );")?;
-        Ok(SettingsDB{database: database})
+    pub fn with(database: Connection) -> SettingsDB {
+        SettingsDB{database}
     }
     
     pub fn set(&self, key: &str, value: &str) -> Result<(), Error> {
-        self.database.execute(format!("DELETE FROM settings WHERE key = '{}';", key))?;
-        self.database.execute(format!("INSERT INTO settings (key, value)
-        VALUES('{}', '{}');",
-        key, value))?;
-        Ok(())
+        execute(&self.database, "INSERT OR REPLACE INTO settings (key, value) VALUES(?, ?);",
+            &[text(key), text(value)])
     }
 
     pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
-        match self.database 
-            .prepare(format!("SELECT key, value
-            FROM settings WHERE key = '{}';",
-            key))?.into_iter().next() {
-                Some(row) => Ok(Some((row.as_ref().unwrap().read::<&str, _>("value")).to_string())),
-                None => Ok(None)
-            }
+        Ok(query(&self.database, "SELECT value FROM settings WHERE key = ?;", &[text(key)])?
+            .first()
+            .map(|row| row.read::<&str, _>("value").to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashStatus {
+    Pending,
+    Valid,
+    Underpaid,
+}
+
+impl HashStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HashStatus::Pending => "pending",
+            HashStatus::Valid => "valid",
+            HashStatus::Underpaid => "underpaid",
+        }
+    }
+
+    fn from_str(status: &str) -> HashStatus {
+        match status {
+            "valid" => HashStatus::Valid,
+            "underpaid" => HashStatus::Underpaid,
+            _ => HashStatus::Pending,
+        }
+    }
+}
+
+//Where an indexed record was published, the TBPUB output of a transaction
+//in a block, and the fee that transaction paid.
+#[derive(Debug, Clone)]
+pub struct TxLocation {
+    pub txid: String,
+    pub vout: u32,
+    pub block_hash: String,
+    pub block_time: u64,
+    //None if the node could not tell the fee
+    pub fee: Option<u64>,
+}
+
+impl TxLocation {
+    //None for records indexed before locations were stored that have not
+    //been backfilled yet.
+    fn from_row(row: &Row) -> Option<TxLocation> {
+        Some(TxLocation{
+            txid: row.read::<Option<&str>, _>("txid")?.to_string(),
+            vout: row.read::<Option<i64>, _>("vout")? as u32,
+            block_hash: row.read::<Option<&str>, _>("block_hash")?.to_string(),
+            block_time: row.read::<Option<i64>, _>("block_time")? as u64,
+            fee: row.read::<Option<i64>, _>("fee").map(|fee| fee as u64),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut result: Value = json!(null);
+        result["txid"] = json!(self.txid);
+        result["vout"] = json!(self.vout);
+        result["block_hash"] = json!(self.block_hash);
+        result["block_time"] = json!(self.block_time);
+        result["fee"] = json!(self.fee);
+        result
+    }
+
+    //Values of the txid, vout, block_hash, block_time and fee columns.
+    fn values(location: &Option<TxLocation>) -> Vec<SqlValue> {
+        match location {
+            Some(location) => vec![text(&location.txid), int(location.vout as u64), text(&location.block_hash),
+                int(location.block_time), location.fee.map(int).unwrap_or(SqlValue::Null)],
+            None => vec![SqlValue::Null; 5]
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HashRecord {
+    pub hash: String,
+    pub block_height: u64,
+    pub price: u64,
+    pub status: HashStatus,
+    pub algorithm: HashAlgorithm,
+    pub location: Option<TxLocation>,
+}
+
+impl HashRecord {
+    fn from_row(row: &Row) -> HashRecord {
+        HashRecord{
+            hash: row.read::<&str, _>("hash").to_string(),
+            block_height: row.read::<i64, _>("block_height") as u64,
+            price: row.read::<i64, _>("price") as u64,
+            status: HashStatus::from_str(row.read::<Option<&str>, _>("status").unwrap_or("pending")),
+            algorithm: HashAlgorithm::from_str(row.read::<Option<&str>, _>("algorithm").unwrap_or("sha1")),
+            location: TxLocation::from_row(row),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut result: Value = json!(null);
+        result["hash"] = json!(self.hash);
+        result["block_height"] = json!(self.block_height);
+        result["price"] = json!(self.price);
+        result["status"] = json!(self.status.as_str());
+        result["algorithm"] = json!(self.algorithm.as_str());
+        result["location"] = self.location.as_ref().map(TxLocation::to_json).unwrap_or(json!(null));
+        result
     }
 }
 
 pub struct HashesDB {
-    database: sqlite::Connection,
+    database: Connection,
 }
 
 impl HashesDB {
-    pub fn new(config: &Config) -> Result<HashesDB, Error> {
-        let mut database_path = config.datadir.clone();
-        database_path.push("hashes.db");
-        let database = sqlite::open(database_path)?;
-        database.execute("
-        CREATE TABLE IF NOT EXISTS hashes (
-            hash TEXT PRIMARY KEY,
-            block_height INT,
-            price INT
-        );")?;
-        Ok(HashesDB{database: database})
+    pub fn with(database: Connection) -> HashesDB {
+        HashesDB{database}
     }
     
-    pub fn add(&self, hash: &str, block_height: u64, price: u64) -> Result<(), Error> {
-      if self.database 
-          .prepare(format!("SELECT hash, block_height, price
-          FROM hashes WHERE hash = '{}';",
-          hash))?.into_iter().next().is_some() {return Ok(());}
-        Ok(self.database.execute(format!("
-        INSERT INTO hashes (hash, block_height, price)
-        VALUES('{}', {}, {});",
-        hash, block_height, price))?)
+    //Hashes already indexed only get their location filled in if it is missing.
+    pub fn add(&self, record: &HashRecord) -> Result<(), Error> {
+        let mut values = TxLocation::values(&record.location);
+        values.push(text(&record.hash));
+        if self.get(&record.hash)?.is_some() {
+            return execute(&self.database, "UPDATE hashes
+            SET txid = ?, vout = ?, block_hash = ?, block_time = ?, fee = ?
+            WHERE hash = ? AND txid IS NULL;", &values);
+        }
+        values.extend([int(record.block_height), int(record.price), text(record.status.as_str()),
+            text(record.algorithm.as_str())]);
+        execute(&self.database, "
+        INSERT INTO hashes (txid, vout, block_hash, block_time, fee, hash, block_height, price, status, algorithm)
+        VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", &values)
+    }
+
+    pub fn get_by_txid(&self, txid: &str) -> Result<Option<HashRecord>, Error> {
+        Ok(query(&self.database, "SELECT * FROM hashes WHERE txid = ?;", &[text(txid)])?
+            .first()
+            .map(HashRecord::from_row))
+    }
+
+    //Block heights of hashes indexed before locations were stored.
+    pub fn missing_locations(&self) -> Result<Vec<u64>, Error> {
+        Ok(query(&self.database, "SELECT block_height FROM hashes WHERE txid IS NULL;", &[])?
+            .iter()
+            .map(|row| row.read::<i64, _>("block_height") as u64)
+            .collect())
+    }
+
+    pub fn remove_above(&self, block_height: u64) -> Result<(), Error> {
+        execute(&self.database, "DELETE FROM hashes WHERE block_height > ?;", &[int(block_height)])
+    }
+
+    pub fn get(&self, hash: &str) -> Result<Option<HashRecord>, Error> {
+        Ok(query(&self.database, "SELECT * FROM hashes WHERE hash = ?;", &[text(hash)])?
+            .first()
+            .map(HashRecord::from_row))
+    }
+
+    //Hashes whose book has not been resolved yet.
+    pub fn pending(&self) -> Result<Vec<HashRecord>, Error> {
+        Ok(query(&self.database, "SELECT *
+            FROM hashes WHERE status = 'pending' OR status IS NULL
+            ORDER BY block_height;", &[])?
+            .iter()
+            .map(HashRecord::from_row)
+            .collect())
+    }
+
+    pub fn set_status(&self, hash: &str, status: HashStatus) -> Result<(), Error> {
+        execute(&self.database, "UPDATE hashes SET status = ? WHERE hash = ?;",
+            &[text(status.as_str()), text(hash)])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RootDIDRecord {
+    //Hex encoded like every DID in the database
+    pub did: String,
+    pub block_height: u64,
+    pub price: u64,
+    pub public_key: Option<String>,
+    pub location: Option<TxLocation>,
+}
+
+impl RootDIDRecord {
+    fn from_row(row: &Row) -> RootDIDRecord {
+        RootDIDRecord{
+            did: row.read::<&str, _>("did").to_string(),
+            block_height: row.read::<i64, _>("block_height") as u64,
+            price: row.read::<i64, _>("price") as u64,
+            public_key: row.read::<Option<&str>, _>("public_key").map(String::from),
+            location: TxLocation::from_row(row),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut result: Value = json!(null);
+        result["did"] = json!(String::from_utf8_lossy(&hex_decode(&self.did).unwrap_or_default()));
+        result["block_height"] = json!(self.block_height);
+        result["price"] = json!(self.price);
+        result["public_key"] = json!(self.public_key);
+        result["location"] = self.location.as_ref().map(TxLocation::to_json).unwrap_or(json!(null));
+        result
     }
 }
 
 pub struct RootDIDsDB {
-    database: sqlite::Connection,
+    database: Connection,
 }
 
 impl RootDIDsDB {
-    pub fn new(config: &Config) -> Result<RootDIDsDB, Error> {
-        let mut database_path = config.datadir.clone();
-        database_path.push("rootdids.db");
-        let database = sqlite::open(database_path)?;
-        database.execute("
-        CREATE TABLE IF NOT EXISTS rootdids (
-            did TEXT PRIMARY KEY,
-            block_height INT,
-            price INT
-        );")?;
-        Ok(RootDIDsDB{database: database})
+    pub fn with(database: Connection) -> RootDIDsDB {
+        RootDIDsDB{database}
     }
     
-    pub fn add(&self, did: &str, block_height: u64, price: u64) -> Result<(), Error> {
-      if self.database 
-          .prepare(format!("SELECT did, block_height, price
-          FROM rootdids WHERE did = '{}';",
-          did))?.into_iter().next().is_some() {return Ok(());}
-        Ok(self.database.execute(format!("
-        INSERT INTO rootdids (did, block_height, price)
-        VALUES('{}', {}, {});",
-        did, block_height, price))?)
+    //DIDs already indexed only get their location filled in if it is missing.
+    pub fn add(&self, record: &RootDIDRecord) -> Result<(), Error> {
+        let mut values = TxLocation::values(&record.location);
+        values.push(text(&record.did));
+        if self.get(&record.did)?.is_some() {
+            return execute(&self.database, "UPDATE rootdids
+            SET txid = ?, vout = ?, block_hash = ?, block_time = ?, fee = ?
+            WHERE did = ? AND txid IS NULL;", &values);
+        }
+        values.extend([int(record.block_height), int(record.price),
+            record.public_key.as_deref().map(text).unwrap_or(SqlValue::Null)]);
+        execute(&self.database, "
+        INSERT INTO rootdids (txid, vout, block_hash, block_time, fee, did, block_height, price, public_key)
+        VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?);", &values)
+    }
+
+    pub fn get(&self, did: &str) -> Result<Option<RootDIDRecord>, Error> {
+        Ok(query(&self.database, "SELECT * FROM rootdids WHERE did = ?;", &[text(did)])?
+            .first()
+            .map(RootDIDRecord::from_row))
+    }
+
+    pub fn get_by_txid(&self, txid: &str) -> Result<Option<RootDIDRecord>, Error> {
+        Ok(query(&self.database, "SELECT * FROM rootdids WHERE txid = ?;", &[text(txid)])?
+            .first()
+            .map(RootDIDRecord::from_row))
+    }
+
+    //Block heights of DIDs indexed before locations were stored.
+    pub fn missing_locations(&self) -> Result<Vec<u64>, Error> {
+        Ok(query(&self.database, "SELECT block_height FROM rootdids WHERE txid IS NULL;", &[])?
+            .iter()
+            .map(|row| row.read::<i64, _>("block_height") as u64)
+            .collect())
+    }
+
+    pub fn remove_above(&self, block_height: u64) -> Result<(), Error> {
+        execute(&self.database, "DELETE FROM rootdids WHERE block_height > ?;", &[int(block_height)])
+    }
+
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(query(&self.database, "SELECT did FROM rootdids ORDER BY block_height;", &[])?
+            .iter()
+            .map(|row| row.read::<&str, _>("did").to_string())
+            .collect())
+    }
+}
+
+pub struct BlocksDB {
+    database: Connection,
+}
+
+impl BlocksDB {
+    pub fn with(database: Connection) -> BlocksDB {
+        BlocksDB{database}
+    }
+
+    pub fn set(&self, block_height: u64, hash: &str) -> Result<(), Error> {
+        execute(&self.database, "INSERT OR REPLACE INTO blocks (block_height, hash) VALUES(?, ?);",
+            &[int(block_height), text(hash)])
+    }
+
+    pub fn get(&self, block_height: u64) -> Result<Option<String>, Error> {
+        Ok(query(&self.database, "SELECT hash FROM blocks WHERE block_height = ?;", &[int(block_height)])?
+            .first()
+            .map(|row| row.read::<&str, _>("hash").to_string()))
+    }
+
+    pub fn remove_above(&self, block_height: u64) -> Result<(), Error> {
+        execute(&self.database, "DELETE FROM blocks WHERE block_height > ?;", &[int(block_height)])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoserRecord {
+    pub txid: String,
+    pub block_height: u64,
+    pub price: u64,
+    pub data: String,
+    pub is_hash: bool,
+    //Set for transactions with more than one TBPUB output
+    pub invalid: bool,
+    //Txid of the TBPUB Transaction that won the block, empty if none did
+    pub winner: String,
+}
+
+pub struct LosersDB {
+    database: Connection,
+}
+
+//TBPUB Transactions that were outbid by the winner of their block, kept to
+//audit the auction of every block.
+impl LosersDB {
+    pub fn with(database: Connection) -> LosersDB {
+        LosersDB{database}
+    }
+
+    pub fn add(&self, record: &LoserRecord) -> Result<(), Error> {
+        execute(&self.database, "
+        INSERT OR REPLACE INTO losers (txid, block_height, price, data, is_hash, invalid, winner)
+        VALUES(?, ?, ?, ?, ?, ?, ?);",
+        &[text(&record.txid), int(record.block_height), int(record.price), text(&record.data),
+            int(record.is_hash as u64), int(record.invalid as u64), text(&record.winner)])
+    }
+
+    //Losers of a block, best ranked first.
+    pub fn list(&self, block_height: u64) -> Result<Vec<LoserRecord>, Error> {
+        Ok(query(&self.database, "SELECT txid, block_height, price, data, is_hash, invalid, winner
+            FROM losers WHERE block_height = ?
+            ORDER BY price DESC, txid;", &[int(block_height)])?
+            .iter()
+            .map(|row| LoserRecord{
+                txid: row.read::<&str, _>("txid").to_string(),
+                block_height: row.read::<i64, _>("block_height") as u64,
+                price: row.read::<i64, _>("price") as u64,
+                data: row.read::<&str, _>("data").to_string(),
+                is_hash: row.read::<i64, _>("is_hash") != 0,
+                invalid: row.read::<i64, _>("invalid") != 0,
+                winner: row.read::<&str, _>("winner").to_string(),
+            })
+            .collect())
+    }
+
+    pub fn remove_above(&self, block_height: u64) -> Result<(), Error> {
+        execute(&self.database, "DELETE FROM losers WHERE block_height > ?;", &[int(block_height)])
+    }
+}
+
+pub struct SharesDB {
+    database: Connection,
+}
+
+//Books served to other Root Nodes, each book is only shared once per peer.
+impl SharesDB {
+    pub fn with(database: Connection) -> SharesDB {
+        SharesDB{database}
+    }
+
+    pub fn add(&self, hash: &str, did: &str) -> Result<(), Error> {
+        execute(&self.database, "INSERT OR IGNORE INTO shares (hash, did) VALUES(?, ?);",
+            &[text(hash), text(did)])
+    }
+
+    pub fn has(&self, hash: &str, did: &str) -> Result<bool, Error> {
+        Ok(!query(&self.database, "SELECT hash FROM shares WHERE hash = ? AND did = ?;",
+            &[text(hash), text(did)])?.is_empty())
+    }
+
+    //Number of books shared with every peer.
+    pub fn stats(&self) -> Result<Vec<(String, u64)>, Error> {
+        Ok(query(&self.database, "SELECT did, COUNT(hash) AS books
+            FROM shares GROUP BY did ORDER BY did;", &[])?
+            .iter()
+            .map(|row| (row.read::<&str, _>("did").to_string(), row.read::<i64, _>("books") as u64))
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DIDDocument {
+    pub endpoint: Option<String>,
+    //Hex encoded Ed25519 key, only kept for methods that do not embed it
+    pub public_key: Option<String>,
+    pub sequence: u64,
+    pub fetched: u64,
+}
+
+pub struct DIDDocumentsDB {
+    database: Connection,
+}
+
+//Service endpoints and keys resolved from DID documents, with the sequence
+//number of the document and when it was fetched.
+impl DIDDocumentsDB {
+    pub fn with(database: Connection) -> DIDDocumentsDB {
+        DIDDocumentsDB{database}
+    }
+
+    pub fn set(&self, did: &str, document: &DIDDocument) -> Result<(), Error> {
+        let optional = |value: &Option<String>| value.as_deref().map(text).unwrap_or(SqlValue::Null);
+        execute(&self.database, "
+        INSERT OR REPLACE INTO diddocuments (did, endpoint, sequence, fetched, public_key)
+        VALUES(?, ?, ?, ?, ?);",
+        &[text(did), optional(&document.endpoint), int(document.sequence), int(document.fetched),
+            optional(&document.public_key)])
+    }
+
+    pub fn get(&self, did: &str) -> Result<Option<DIDDocument>, Error> {
+        Ok(query(&self.database, "SELECT endpoint, sequence, fetched, public_key
+            FROM diddocuments WHERE did = ?;", &[text(did)])?
+            .first()
+            .map(|row| DIDDocument{
+                endpoint: row.read::<Option<&str>, _>("endpoint").map(String::from),
+                public_key: row.read::<Option<&str>, _>("public_key").map(String::from),
+                sequence: row.read::<i64, _>("sequence") as u64,
+                fetched: row.read::<i64, _>("fetched") as u64,
+            }))
+    }
+}
+
+
+//The default storage, every table in the one sqlite database.
+pub struct SqliteStorage {
+    database: Connection,
+    settings: SettingsDB,
+    hashes: HashesDB,
+    rootdids: RootDIDsDB,
+    blocks: BlocksDB,
+    losers: LosersDB,
+    shares: SharesDB,
+    documents: DIDDocumentsDB,
+}
+
+impl SqliteStorage {
+    pub fn new(datadir: &Path) -> Result<SqliteStorage, Error> {
+        let database = open(datadir)?;
+        Ok(SqliteStorage{
+            settings: SettingsDB::with(database.clone()),
+            hashes: HashesDB::with(database.clone()),
+            rootdids: RootDIDsDB::with(database.clone()),
+            blocks: BlocksDB::with(database.clone()),
+            losers: LosersDB::with(database.clone()),
+            shares: SharesDB::with(database.clone()),
+            documents: DIDDocumentsDB::with(database.clone()),
+            database,
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn setting(&self, key: &str) -> Result<Option<String>, Error> {
+        self.settings.get(key)
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.settings.set(key, value)
+    }
+
+    fn hash(&self, hash: &str) -> Result<Option<HashRecord>, Error> {
+        self.hashes.get(hash)
+    }
+
+    fn hash_by_txid(&self, txid: &str) -> Result<Option<HashRecord>, Error> {
+        self.hashes.get_by_txid(txid)
+    }
+
+    fn pending_hashes(&self) -> Result<Vec<HashRecord>, Error> {
+        self.hashes.pending()
+    }
+
+    fn set_hash_status(&self, hash: &str, status: HashStatus) -> Result<(), Error> {
+        self.hashes.set_status(hash, status)
+    }
+
+    fn did(&self, did: &str) -> Result<Option<RootDIDRecord>, Error> {
+        self.rootdids.get(did)
+    }
+
+    fn did_by_txid(&self, txid: &str) -> Result<Option<RootDIDRecord>, Error> {
+        self.rootdids.get_by_txid(txid)
+    }
+
+    fn dids(&self) -> Result<Vec<String>, Error> {
+        self.rootdids.list()
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<Option<String>, Error> {
+        self.blocks.get(block_height)
+    }
+
+    fn losers(&self, block_height: u64) -> Result<Vec<LoserRecord>, Error> {
+        self.losers.list(block_height)
+    }
+
+    fn missing_locations(&self) -> Result<Vec<u64>, Error> {
+        let mut heights = self.hashes.missing_locations()?;
+        heights.extend(self.rootdids.missing_locations()?);
+        heights.sort();
+        heights.dedup();
+        Ok(heights)
+    }
+
+    fn add_record(&self, record: &Record) -> Result<(), Error> {
+        match record {
+            Record::Hash(record) => self.hashes.add(record),
+            Record::RootDID(record) => self.rootdids.add(record)
+        }
+    }
+
+    fn add_blocks(&self, blocks: &[IndexedBlock]) -> Result<(), Error> {
+        let last = match blocks.last() {
+            Some(last) => last.block_height,
+            None => return Ok(())
+        };
+        transaction(&self.database, || {
+            for block in blocks {
+                for loser in &block.losers {
+                    self.losers.add(loser)?;
+                }
+                if let Some(winner) = &block.winner {
+                    self.add_record(winner)?;
+                }
+                self.blocks.set(block.block_height, &block.block_hash)?;
+            }
+            self.settings.set(BLOCK_HEIGHT, &(last + 1).to_string())
+        })
+    }
+
+    fn rollback(&self, fork_point: u64) -> Result<(), Error> {
+        transaction(&self.database, || {
+            self.hashes.remove_above(fork_point)?;
+            self.rootdids.remove_above(fork_point)?;
+            self.blocks.remove_above(fork_point)?;
+            self.losers.remove_above(fork_point)?;
+            self.settings.set(BLOCK_HEIGHT, &(fork_point + 1).to_string())
+        })
+    }
+
+    fn add_share(&self, hash: &str, did: &str) -> Result<(), Error> {
+        self.shares.add(hash, did)
+    }
+
+    fn has_share(&self, hash: &str, did: &str) -> Result<bool, Error> {
+        self.shares.has(hash, did)
+    }
+
+    fn share_stats(&self) -> Result<Vec<(String, u64)>, Error> {
+        self.shares.stats()
+    }
+
+    fn did_document(&self, did: &str) -> Result<Option<DIDDocument>, Error> {
+        self.documents.get(did)
+    }
+
+    fn set_did_document(&self, did: &str, document: &DIDDocument) -> Result<(), Error> {
+        self.documents.set(did, document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    //Writes a file the way the first versions did.
+    fn legacy_file(datadir: &Path, file: &str, statements: &str) {
+        sqlite::open(datadir.join(file)).unwrap().execute(statements).unwrap();
+    }
+
+    #[test]
+    fn import_legacy() {
+        let datadir = test_util::temp_dir();
+        let did = hex_encode(test_util::did(1));
+        legacy_file(&datadir, "settings.db", "CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);
+            INSERT INTO settings VALUES('block_height', '830000');");
+        legacy_file(&datadir, "hashes.db", "CREATE TABLE hashes (hash TEXT PRIMARY KEY, block_height INT, price INT);
+            INSERT INTO hashes VALUES('0102', 829000, 5000);");
+        legacy_file(&datadir, "rootdids.db", &format!("CREATE TABLE rootdids (did TEXT PRIMARY KEY, block_height INT, price INT);
+            INSERT INTO rootdids VALUES('{}', 828000, 6000);
+            INSERT INTO rootdids VALUES('{}', 828001, 6000);", did, hex_encode("did:dht:nonsense")));
+        //Files no version ever kept are left alone
+        legacy_file(&datadir, "blocks.db", "CREATE TABLE blocks (block_height INT, hash TEXT);
+            INSERT INTO blocks VALUES(1, 'aa');");
+
+        let storage = SqliteStorage::new(&datadir).unwrap();
+        assert_eq!(storage.setting(BLOCK_HEIGHT).unwrap(), Some("830000".to_string()));
+        let hash = storage.hash("0102").unwrap().unwrap();
+        assert_eq!((hash.block_height, hash.price, hash.status), (829000, 5000, HashStatus::Pending));
+        let record = storage.did(&did).unwrap().unwrap();
+        assert_eq!(record.public_key, Some(hex_encode(crate::did::dht_public_key(&test_util::did(1)).unwrap().as_bytes())));
+        assert_eq!(storage.dids().unwrap(), vec![did]);
+        assert_eq!(storage.block_hash(1).unwrap(), None);
+        assert_eq!(schema_version(&storage.database).unwrap(), MIGRATIONS.len());
+    }
+
+    //Opening a migrated database must not wait for a writer to finish.
+    #[test]
+    fn open_without_write_lock() {
+        let datadir = test_util::temp_dir();
+        let writer = open(&datadir).unwrap();
+        writer.execute("BEGIN IMMEDIATE;").unwrap();
+        let started = std::time::Instant::now();
+        let reader = open(&datadir).unwrap();
+        assert!(started.elapsed().as_millis() < BUSY_TIMEOUT as u128 / 2);
+        assert_eq!(schema_version(&reader).unwrap(), MIGRATIONS.len());
+        writer.execute("COMMIT;").unwrap();
     }
 }
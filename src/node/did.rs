@@ -0,0 +1,170 @@
+use crate::Error;
+use crate::{hex_encode, hex_decode};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+pub const DID_DHT_PREFIX: &str = "did:dht:";
+pub const DID_DHT_SUFFIX_LENGTH: usize = 52;
+const ZBASE32_ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+//z-base-32 as used by did:dht, 5 bits per character most significant first.
+//https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+pub fn zbase32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ZBASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ZBASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+//Returns None on characters outside the alphabet or if the bits left over
+//after the last whole byte are not zero, so every value has one encoding.
+pub fn zbase32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for character in data.bytes() {
+        let value = ZBASE32_ALPHABET.iter().position(|&c| c == character)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    if buffer != 0 {return None;}
+    Some(result)
+}
+
+//The Ed25519 public key a did:dht identifier is made of.
+pub fn dht_public_key(did: &str) -> Option<VerifyingKey> {
+    let suffix = did.strip_prefix(DID_DHT_PREFIX)?;
+    if suffix.len() != DID_DHT_SUFFIX_LENGTH {return None;}
+    let key: [u8; PUBLIC_KEY_LENGTH] = zbase32_decode(suffix)?.try_into().ok()?;
+    VerifyingKey::from_bytes(&key).ok()
+}
+
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let public_key = match public_key.try_into().map(VerifyingKey::from_bytes) {
+        Ok(Ok(public_key)) => public_key,
+        _ => return false
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => public_key.verify_strict(message, &signature).is_ok(),
+        Err(_) => false
+    }
+}
+
+//The key this Root Node signs with, kept hex encoded in datadir/identity.key
+//and created on first start. Its did:dht identifier is what gets published.
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn load(datadir: &Path) -> Result<Identity, Error> {
+        let path = datadir.join("identity.key");
+        if path.exists() {
+            let secret_key: [u8; SECRET_KEY_LENGTH] = hex_decode(read_to_string(&path)?.trim())?.as_slice().try_into()?;
+            return Ok(Identity{signing_key: SigningKey::from_bytes(&secret_key)});
+        }
+        let identity = Identity::generate()?;
+        write(&path, hex_encode(identity.signing_key.to_bytes()))?;
+        Ok(identity)
+    }
+
+    //A new key that is only kept in memory.
+    pub fn generate() -> Result<Identity, Error> {
+        let mut secret_key = [0u8; SECRET_KEY_LENGTH];
+        getrandom::getrandom(&mut secret_key).map_err(std::io::Error::from)?;
+        Ok(Identity{signing_key: SigningKey::from_bytes(&secret_key)})
+    }
+
+    pub fn did(&self) -> String {
+        format!("{}{}", DID_DHT_PREFIX, zbase32_encode(self.signing_key.verifying_key().as_bytes()))
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).to_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Test vector 1 of the did:dht specification, the key is the x of its JWK.
+    const DID: &str = "did:dht:cyuoqaf7itop8ohww4yn5ojg13qaq83r9zihgqntc5i9zwrfdfoo";
+    const PUBLIC_KEY: &str = "60270760bdac60d3c394a6802dc126965d871f24fdebc3385166ebfbd0851961";
+
+    fn with_suffix(suffix: &str) -> String {
+        format!("{}{}", DID_DHT_PREFIX, suffix)
+    }
+
+    #[test]
+    fn known_answer() {
+        assert_eq!(hex_encode(dht_public_key(DID).unwrap().as_bytes()), PUBLIC_KEY);
+        assert_eq!(with_suffix(&zbase32_encode(&hex_decode(PUBLIC_KEY).unwrap())), DID);
+    }
+
+    #[test]
+    fn invalid_characters() {
+        let suffix = &DID[DID_DHT_PREFIX.len()..];
+        //l, v, 0 and 2 are left out of the alphabet and it is lower case only
+        for character in ["l", "v", "0", "2", "O", "="] {
+            let did = with_suffix(&format!("{}{}", character, &suffix[1..]));
+            assert!(dht_public_key(&did).is_none(), "{}", did);
+        }
+        assert!(zbase32_decode("yb y").is_none());
+    }
+
+    #[test]
+    fn wrong_length() {
+        let suffix = &DID[DID_DHT_PREFIX.len()..];
+        assert!(dht_public_key(&with_suffix(&suffix[..51])).is_none());
+        assert!(dht_public_key(&with_suffix(&format!("{}y", suffix))).is_none());
+        assert!(dht_public_key("did:dht:").is_none());
+        assert!(dht_public_key(&format!("did:key:{}", suffix)).is_none());
+    }
+
+    //The last character carries one bit of the key and four zero pad bits.
+    #[test]
+    fn trailing_bits() {
+        let suffix = &DID[DID_DHT_PREFIX.len()..];
+        assert!(suffix.ends_with('o'));
+        for last in ["t", "1", "u"] {
+            let did = with_suffix(&format!("{}{}", &suffix[..51], last));
+            assert!(dht_public_key(&did).is_none(), "{}", did);
+        }
+        assert_eq!(zbase32_decode("yy"), Some(vec![0]));
+        assert_eq!(zbase32_decode("yb"), None);
+    }
+
+    #[test]
+    fn signatures() {
+        let identity = Identity{signing_key: SigningKey::from_bytes(&[1; SECRET_KEY_LENGTH])};
+        let other = Identity{signing_key: SigningKey::from_bytes(&[2; SECRET_KEY_LENGTH])};
+        let public_key = dht_public_key(&identity.did()).unwrap().to_bytes();
+        let signature = identity.sign(b"message");
+        assert!(verify(&public_key, b"message", &signature));
+        assert!(!verify(&public_key, b"messages", &signature));
+        assert!(!verify(&public_key, b"message", &other.sign(b"message")));
+        assert!(!verify(&public_key, b"message", &signature[1..]));
+        assert!(!verify(&public_key, b"message", &[]));
+        assert!(!verify(&public_key[1..], b"message", &signature));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::did::dht_public_key;
+
+use ed25519_dalek::{VerifyingKey, PUBLIC_KEY_LENGTH};
+
+pub const DID_KEY_PREFIX: &str = "did:key:";
+pub const DID_WEB_PREFIX: &str = "did:web:";
+//Multicodec prefix of an Ed25519 public key
+const ED25519_MULTICODEC: [u8; 2] = [0xed, 0x01];
+const MAXIMUM_DID_SIZE: usize = 200;
+
+//A DID method Root Nodes can be published under. FLAG_METHOD_DID payloads
+//start with the id of the method followed by the DID itself.
+pub trait DIDMethod: Sync {
+    fn id(&self) -> u8;
+    fn prefix(&self) -> &'static str;
+    //Whether the DID is well formed for this method
+    fn validate(&self, did: &str) -> bool;
+    //The Ed25519 key the DID is made of, None if the key is only known once
+    //the DID document is resolved
+    fn public_key(&self, _did: &str) -> Option<VerifyingKey> {
+        None
+    }
+}
+
+pub struct DHTMethod;
+
+impl DIDMethod for DHTMethod {
+    fn id(&self) -> u8 {0x00}
+    fn prefix(&self) -> &'static str {crate::did::DID_DHT_PREFIX}
+    fn validate(&self, did: &str) -> bool {
+        dht_public_key(did).is_some()
+    }
+    fn public_key(&self, did: &str) -> Option<VerifyingKey> {
+        dht_public_key(did)
+    }
+}
+
+//did:key identifiers are the multibase base58btc encoding of the multicodec
+//key, only Ed25519 keys are accepted as those are what Root Nodes sign with.
+//https://w3c-ccg.github.io/did-method-key/
+pub struct KeyMethod;
+
+impl DIDMethod for KeyMethod {
+    fn id(&self) -> u8 {0x01}
+    fn prefix(&self) -> &'static str {DID_KEY_PREFIX}
+    fn validate(&self, did: &str) -> bool {
+        self.public_key(did).is_some()
+    }
+    fn public_key(&self, did: &str) -> Option<VerifyingKey> {
+        multibase_public_key(did.strip_prefix(DID_KEY_PREFIX)?)
+    }
+}
+
+//An Ed25519 key as a base58btc multibase multicodec string, z6Mk...
+pub fn multibase_public_key(multibase: &str) -> Option<VerifyingKey> {
+    let bytes = bitcoin::base58::decode(multibase.strip_prefix('z')?).ok()?;
+    let key: [u8; PUBLIC_KEY_LENGTH] = bytes.strip_prefix(&ED25519_MULTICODEC)?.try_into().ok()?;
+    VerifyingKey::from_bytes(&key).ok()
+}
+
+//did:web identifiers are a domain, with the port percent encoded, followed by
+//optional colon separated path segments. Their key is in the DID document.
+//https://w3c-ccg.github.io/did-method-web/
+pub struct WebMethod;
+
+impl WebMethod {
+    //Where the DID document of a did:web identifier is served.
+    pub fn document_url(&self, did: &str) -> Option<String> {
+        if !self.validate(did) {return None;}
+        let mut segments = did[DID_WEB_PREFIX.len()..].split(':');
+        let domain = segments.next()?.replacen("%3A", ":", 1);
+        let path: Vec<&str> = segments.collect();
+        Some(match path.is_empty() {
+            true => format!("https://{}/.well-known/did.json", domain),
+            false => format!("https://{}/{}/did.json", domain, path.join("/"))
+        })
+    }
+}
+
+impl DIDMethod for WebMethod {
+    fn id(&self) -> u8 {0x02}
+    fn prefix(&self) -> &'static str {DID_WEB_PREFIX}
+    fn validate(&self, did: &str) -> bool {
+        if did.len() > MAXIMUM_DID_SIZE {return false;}
+        let mut segments = match did.strip_prefix(DID_WEB_PREFIX) {
+            Some(identifier) => identifier.split(':'),
+            None => return false
+        };
+        let (host, port) = match segments.next() {
+            Some(domain) => domain.split_once("%3A").unwrap_or((domain, "")),
+            None => return false
+        };
+        let host_valid = !host.is_empty() && host.contains('.')
+            && host.split('.').all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'))
+            && host.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'.' || c == b'-');
+        let port_valid = port.is_empty() || port.parse::<u16>().is_ok_and(|port| port != 0);
+        let path_valid = segments.all(|segment| !segment.is_empty() && segment != "." && segment != ".."
+            && segment.bytes().all(|c| c.is_ascii_alphanumeric() || b"-._~".contains(&c)));
+        host_valid && port_valid && path_valid
+    }
+}
+
+pub static METHODS: [&dyn DIDMethod; 3] = [&DHTMethod, &KeyMethod, &WebMethod];
+
+//The method of a DID, None if the method is not supported or the DID is not
+//well formed for it.
+pub fn method(did: &str) -> Option<&'static dyn DIDMethod> {
+    METHODS.iter()
+        .find(|method| did.starts_with(method.prefix()))
+        .filter(|method| method.validate(did))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    //The key of test 1 of RFC 8032 and its did:key identifier.
+    const SECRET_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const DID_KEY: &str = "did:key:z6MktwupdmLXVVqTzCw4i46r4uGyosGXRnR3XjN4Zq7oMMsw";
+
+    #[test]
+    fn did_key_known_answer() {
+        let secret_key: [u8; 32] = crate::hex_decode(SECRET_KEY).unwrap().try_into().unwrap();
+        let public_key = KeyMethod.public_key(DID_KEY).unwrap();
+        assert_eq!(public_key, SigningKey::from_bytes(&secret_key).verifying_key());
+        assert_eq!(crate::hex_encode(public_key.as_bytes()), PUBLIC_KEY);
+        assert_eq!(method(DID_KEY).map(|method| method.id()), Some(KeyMethod.id()));
+    }
+
+    #[test]
+    fn multibase() {
+        let multibase = &DID_KEY[DID_KEY_PREFIX.len()..];
+        assert!(multibase_public_key(multibase).is_some());
+        //Only base58btc, as the same key in base16 is not accepted
+        assert!(multibase_public_key(&format!("f{}{}", "ed01", PUBLIC_KEY)).is_none());
+        assert!(multibase_public_key(&multibase[1..]).is_none());
+        assert!(multibase_public_key(&multibase[..multibase.len() - 1]).is_none());
+        //The same key with the X25519 multicodec
+        assert!(multibase_public_key("z6LSrApwZptxFR4jy6U8Z8exYPwTqSXniWLqihApE1oK9WsK").is_none());
+        assert!(method("did:key:z6LSrApwZptxFR4jy6U8Z8exYPwTqSXniWLqihApE1oK9WsK").is_none());
+    }
+
+    #[test]
+    fn did_web_urls() {
+        let url = |did: &str| WebMethod.document_url(did);
+        assert_eq!(url("did:web:example.com").unwrap(), "https://example.com/.well-known/did.json");
+        assert_eq!(url("did:web:example.com%3A3000").unwrap(), "https://example.com:3000/.well-known/did.json");
+        assert_eq!(url("did:web:example.com:user:alice").unwrap(), "https://example.com/user/alice/did.json");
+        assert_eq!(url("did:web:w3c-ccg.github.io%3A8443:user:alice").unwrap(),
+            "https://w3c-ccg.github.io:8443/user/alice/did.json");
+        for did in ["did:web:localhost", "did:web:Example.com", "did:web:example.com%3A0", "did:web:example.com%3Ahttp",
+                "did:web:example.com:..", "did:web:example.com::alice", "did:web:example.com:a/b", "did:web:-example.com",
+                "did:key:example.com"] {
+            assert!(url(did).is_none(), "{}", did);
+        }
+        assert!(url(&format!("did:web:{}.com", "a".repeat(MAXIMUM_DID_SIZE))).is_none());
+    }
+
+    #[test]
+    fn methods() {
+        let dht = "did:dht:cyuoqaf7itop8ohww4yn5ojg13qaq83r9zihgqntc5i9zwrfdfoo";
+        assert_eq!(method(dht).map(|method| method.id()), Some(DHTMethod.id()));
+        assert_eq!(method("did:web:example.com").map(|method| method.id()), Some(WebMethod.id()));
+        assert!(method("did:web:example.com").unwrap().public_key("did:web:example.com").is_none());
+        assert!(method("did:example:123").is_none());
+        assert!(method("did:dht:").is_none());
+    }
+}
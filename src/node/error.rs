@@ -1,3 +1,4 @@
+#[allow(clippy::enum_variant_names)]
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Node HelpMessage...")]
@@ -10,6 +11,21 @@ pub enum Error {
     #[error("Attempt to sign transaction failed.")]
     CouldNotSignTransaction(),
 
+    #[error("Invalid Book: {}", .0)]
+    InvalidBook(String),
+
+    #[error("DID resolution error: {}", .0)]
+    DIDResolution(String),
+
+    #[error("Peer protocol error: {}", .0)]
+    PeerProtocol(String),
+
+    #[error("ZMQ protocol error: {}", .0)]
+    ZMQProtocol(String),
+
+    #[error("Chain source error: {}", .0)]
+    ChainSource(String),
+
     #[error("Wallet not specified, use -wallet= or include wallet= in config file.")]
     NoWallet(),
 
@@ -18,6 +34,8 @@ pub enum Error {
     #[error(transparent)]
     BitcoinAddressError(#[from] bitcoin::address::Error),
     #[error(transparent)]
+    BitcoinHexError(#[from] bitcoin::hashes::hex::Error),
+    #[error(transparent)]
     HexError(#[from] hex::FromHexError),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
@@ -32,5 +50,9 @@ pub enum Error {
     #[error(transparent)]
     SystemTimeError(#[from] std::time::SystemTimeError),
     #[error(transparent)]
+    HTTPError(#[from] Box<ureq::Error>),
+    #[error(transparent)]
+    MerkleTreeError(anyhow::Error),
+    #[error(transparent)]
     TryFromSliceError(#[from] std::array::TryFromSliceError),
 }
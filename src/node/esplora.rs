@@ -0,0 +1,163 @@
+use crate::{Error, Value, hex_encode};
+use crate::chain_source::ChainSource;
+
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+use bitcoin::consensus::{deserialize, serialize};
+use std::io::Read;
+use std::str::FromStr;
+use std::time::Duration;
+
+const ESPLORA_TIMEOUT: Duration = Duration::from_secs(30);
+//No block can be larger than its 4,000,000 weight units
+const MAXIMUM_BLOCK_SIZE: u64 = 4_000_000;
+const MAXIMUM_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+
+//A chain read from an Esplora HTTP API, eg https://blockstream.info/api, for
+//Root Nodes without a Bitcoin Core node of their own.
+//https://github.com/Blockstream/esplora/blob/master/API.md
+pub struct Esplora {
+    url: String,
+}
+
+impl Esplora {
+    pub fn new(url: &str) -> Esplora {
+        Esplora{url: url.trim_end_matches('/').to_string()}
+    }
+
+    //The body of a GET, None if the API answers 404.
+    fn get(&self, path: &str, limit: u64) -> Result<Option<Vec<u8>>, Error> {
+        let response = match ureq::get(&format!("{}{}", self.url, path)).timeout(ESPLORA_TIMEOUT).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(Error::from(Box::new(e)))
+        };
+        let mut body = Vec::new();
+        response.into_reader().take(limit + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > limit {
+            return Err(Error::ChainSource(format!("Response to {} is larger than {} bytes", path, limit)));
+        }
+        Ok(Some(body))
+    }
+
+    fn get_found(&self, path: &str, limit: u64) -> Result<Vec<u8>, Error> {
+        self.get(path, limit)?.ok_or(Error::ChainSource(format!("{} was not found", path)))
+    }
+
+    fn get_text(&self, path: &str) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(&self.get_found(path, MAXIMUM_RESPONSE_SIZE)?).trim().to_string())
+    }
+
+    fn get_json(&self, path: &str) -> Result<Option<Value>, Error> {
+        match self.get(path, MAXIMUM_RESPONSE_SIZE)? {
+            Some(body) => Ok(Some(serde_json::from_slice(&body)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+impl ChainSource for Esplora {
+    fn block_count(&self) -> Result<u64, Error> {
+        Ok(self.get_text("/blocks/tip/height")?.parse()?)
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        Ok(BlockHash::from_str(&self.get_text(&format!("/block-height/{}", block_height))?)?)
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error> {
+        Ok(deserialize(&self.get_found(&format!("/block/{}/raw", block_hash), MAXIMUM_BLOCK_SIZE)?)?)
+    }
+
+    //Esplora has the fee of every transaction, which saves a request for
+    //each transaction spent.
+    fn transaction_fee(&self, tx: &Transaction) -> Result<Option<u64>, Error> {
+        Ok(self.get_json(&format!("/tx/{}", tx.txid()))?.and_then(|tx| tx["fee"].as_u64()))
+    }
+
+    fn mempool(&self) -> Result<Vec<Txid>, Error> {
+        let txids = self.get_json("/mempool/txids")?.unwrap_or_default();
+        Ok(txids.as_array()
+            .map(|txids| txids.iter().filter_map(|txid| Txid::from_str(txid.as_str()?).ok()).collect())
+            .unwrap_or_default())
+    }
+
+    //Unconfirmed transactions known to the API are in its mempool.
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<(u64, u64)>, Error> {
+        let tx = match self.get_json(&format!("/tx/{}", txid))? {
+            Some(tx) if tx["status"]["confirmed"] == false => tx,
+            _ => return Ok(None)
+        };
+        match (tx["fee"].as_u64(), tx["weight"].as_u64()) {
+            (Some(fee), Some(weight)) => Ok(Some((fee, weight.div_ceil(4)))),
+            _ => Ok(None)
+        }
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        match self.get(&format!("/tx/{}/raw", txid), MAXIMUM_BLOCK_SIZE)? {
+            Some(tx) => Ok(Some(deserialize(&tx)?)),
+            None => Ok(None)
+        }
+    }
+
+    //Syncing would fetch every new mempool transaction on its own and public
+    //APIs rate limit far below that.
+    fn tracks_mempool(&self) -> bool {
+        false
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid = ureq::post(&format!("{}/tx", self.url))
+            .timeout(ESPLORA_TIMEOUT)
+            .send_string(&hex_encode(serialize(tx)))
+            .map_err(Box::new)?
+            .into_string()?;
+        Ok(Txid::from_str(txid.trim())?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::MINIMUM_BLOCK_HEIGHT;
+    use std::collections::HashMap;
+
+    #[test]
+    fn esplora() {
+        let block = test_util::chain(&[], 0, 1, 1).remove(0);
+        let tx = block.txdata[0].clone();
+        let txid = tx.txid();
+        let unknown = test_util::hash_transaction(99, [9; 20], 1000).txid();
+        let limited = test_util::hash_transaction(98, [8; 20], 1000).txid();
+        let routes = HashMap::from([
+            ("GET /blocks/tip/height".to_string(), (200, format!("{}\n", MINIMUM_BLOCK_HEIGHT).into_bytes())),
+            (format!("GET /block-height/{}", MINIMUM_BLOCK_HEIGHT), (200, block.block_hash().to_string().into_bytes())),
+            (format!("GET /block/{}/raw", block.block_hash()), (200, serialize(&block))),
+            (format!("GET /tx/{}", txid), (200, br#"{"fee": 1200, "weight": 801, "status": {"confirmed": false}}"#.to_vec())),
+            (format!("GET /tx/{}/raw", txid), (200, serialize(&tx))),
+            (format!("GET /tx/{}", limited), (429, vec![])),
+            ("POST /tx".to_string(), (200, txid.to_string().into_bytes())),
+        ]);
+        let (url, requests) = test_util::http_server(routes);
+        let esplora = Esplora::new(&format!("{}/", url));
+
+        assert_eq!(esplora.block_count().unwrap(), MINIMUM_BLOCK_HEIGHT);
+        assert_eq!(esplora.block_hash(MINIMUM_BLOCK_HEIGHT).unwrap(), block.block_hash());
+        assert_eq!(esplora.block(&block.block_hash()).unwrap(), block);
+        assert_eq!(esplora.transaction_fee(&tx).unwrap(), Some(1200));
+        assert_eq!(esplora.mempool_entry(&txid).unwrap(), Some((1200, 201)));
+        assert_eq!(esplora.transaction(&txid).unwrap(), Some(tx.clone()));
+        //Unknown is not an error, being rate limited is
+        assert_eq!(esplora.transaction(&unknown).unwrap(), None);
+        assert_eq!(esplora.mempool_entry(&unknown).unwrap(), None);
+        assert!(esplora.mempool_entry(&limited).is_err());
+        assert!(esplora.block_hash(MINIMUM_BLOCK_HEIGHT + 1).is_err());
+        assert!(!esplora.tracks_mempool());
+
+        assert_eq!(esplora.broadcast(&tx).unwrap(), txid);
+        let broadcast = requests.try_iter().find(|(route, _)| route == "POST /tx").unwrap();
+        assert_eq!(broadcast.1, hex_encode(serialize(&tx)).into_bytes());
+    }
+}
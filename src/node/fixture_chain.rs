@@ -0,0 +1,197 @@
+use crate::{Error, hex_decode};
+use crate::chain_source::ChainSource;
+
+use bitcoin::{Block, BlockHash, Transaction, Txid};
+use bitcoin::consensus::deserialize;
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{metadata, read_to_string};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(Default)]
+struct Fixture {
+    blocks: BTreeMap<u64, Block>,
+    //Mempool transactions and the fee each paid
+    mempool: HashMap<Txid, (Transaction, u64)>,
+    modified: Option<SystemTime>,
+}
+
+//A chain read from a fixture file instead of Bitcoin Core, so a scan can be
+//run against canned blocks. Every line of the file is one of
+//  block <height> <hex encoded block>
+//  mempool <fee> <hex encoded transaction>
+//and blank lines or lines starting with # are skipped. The file is read again
+//whenever it changes, so rewriting the blocks above some height is a reorg.
+//Blocks carry no fees.
+pub struct FixtureChain {
+    path: PathBuf,
+    fixture: RefCell<Fixture>,
+}
+
+impl FixtureChain {
+    pub fn new(path: PathBuf) -> FixtureChain {
+        FixtureChain{path, fixture: RefCell::new(Fixture::default())}
+    }
+
+    fn parse(data: &str) -> Result<Fixture, Error> {
+        let mut fixture = Fixture::default();
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {continue;}
+            match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["block", height, block] => {
+                    fixture.blocks.insert(height.parse()?, deserialize(&hex_decode(block)?)?);
+                },
+                ["mempool", fee, tx] => {
+                    let tx: Transaction = deserialize(&hex_decode(tx)?)?;
+                    fixture.mempool.insert(tx.txid(), (tx, fee.parse()?));
+                },
+                _ => return Err(Error::ChainSource(format!("Invalid fixture line: {}", line)))
+            }
+        }
+        Ok(fixture)
+    }
+
+    //Reads the file again if it changed since it was last read.
+    fn load(&self) -> Result<Ref<'_, Fixture>, Error> {
+        let modified = metadata(&self.path)?.modified()?;
+        if self.fixture.borrow().modified != Some(modified) {
+            let mut fixture = FixtureChain::parse(&read_to_string(&self.path)?)?;
+            fixture.modified = Some(modified);
+            *self.fixture.borrow_mut() = fixture;
+        }
+        Ok(self.fixture.borrow())
+    }
+}
+
+impl ChainSource for FixtureChain {
+    fn block_count(&self) -> Result<u64, Error> {
+        self.load()?.blocks.keys().next_back().copied()
+            .ok_or(Error::ChainSource("Fixture has no blocks".to_string()))
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        self.load()?.blocks.get(&block_height).map(|block| block.block_hash())
+            .ok_or(Error::ChainSource(format!("Fixture has no block at height {}", block_height)))
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error> {
+        self.load()?.blocks.values().find(|block| block.block_hash() == *block_hash).cloned()
+            .ok_or(Error::ChainSource(format!("Fixture has no block {}", block_hash)))
+    }
+
+    fn transaction_fee(&self, _tx: &Transaction) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    fn mempool(&self) -> Result<Vec<Txid>, Error> {
+        Ok(self.load()?.mempool.keys().copied().collect())
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<(u64, u64)>, Error> {
+        Ok(self.load()?.mempool.get(txid).map(|(tx, fee)| (*fee, tx.vsize() as u64)))
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let fixture = self.load()?;
+        if let Some((tx, _)) = fixture.mempool.get(txid) {return Ok(Some(tx.clone()));}
+        Ok(fixture.blocks.values()
+            .flat_map(|block| block.txdata.iter())
+            .find(|tx| tx.txid() == *txid)
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Mempool, hex_encode, MINIMUM_BLOCK_HEIGHT, MINIMUM_TBPUB_TX_PRICE};
+    use crate::chain_source;
+    use crate::scanner::{Scanner, ScanProgress};
+    use crate::storage::StorageHandle;
+    use crate::test_util;
+    use bitcoin::consensus::serialize;
+    use std::fs::{write, File};
+    use std::path::Path;
+    use std::time::Duration;
+
+    //Writes the blocks from MINIMUM_BLOCK_HEIGHT on and the mempool. Every
+    //version gets a later modification time so it is always read again.
+    fn write_fixture(path: &Path, version: u64, blocks: &[Block], mempool: &[(Transaction, u64)]) {
+        let mut data = String::from("# fixture\n\n");
+        for (index, block) in blocks.iter().enumerate() {
+            data += &format!("block {} {}\n", MINIMUM_BLOCK_HEIGHT + index as u64, hex_encode(serialize(block)));
+        }
+        for (tx, fee) in mempool {
+            data += &format!("mempool {} {}\n", fee, hex_encode(serialize(tx)));
+        }
+        write(path, data).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + version);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn pipelined_scan_and_reorg() {
+        let path = test_util::temp_dir().join("chain.fixture");
+        let first = test_util::chain(&[], 0, 10, 1);
+        write_fixture(&path, 0, &first, &[]);
+        let config = Config{chainfixture: Some(path.clone()), scanworkers: 3, scanbatch: 4, ..test_util::config()};
+        let storage = StorageHandle::memory().unwrap();
+        let chain = chain_source::open(&config).unwrap();
+        let mut scanner = Scanner::new(&config, &storage, MINIMUM_BLOCK_HEIGHT, Mempool::default(), ScanProgress::default()).unwrap();
+        scanner.scan_pipelined(chain.as_ref(), chain.block_count().unwrap()).unwrap();
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &first);
+        assert!(!scanner.check_tip(chain.as_ref()).unwrap());
+
+        //A longer chain forking below the tip is found while scanning
+        let second = test_util::chain(&first, 5, 13, 2);
+        write_fixture(&path, 1, &second, &[]);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            scanner.scan_pipelined(chain.as_ref(), chain.block_count().unwrap()).unwrap();
+        }
+        test_util::assert_indexed(index.as_ref(), &second);
+        for block in &first[5..] {
+            test_util::assert_not_indexed(index.as_ref(), block);
+        }
+
+        //A chain of the same length replacing the tip is found while waiting at it
+        let third = test_util::chain(&second, 12, 13, 3);
+        write_fixture(&path, 2, &third, &[]);
+        assert!(scanner.check_tip(chain.as_ref()).unwrap());
+        assert_eq!(scanner.block_height, MINIMUM_BLOCK_HEIGHT + 12);
+        scanner.scan_pipelined(chain.as_ref(), chain.block_count().unwrap()).unwrap();
+        test_util::assert_indexed(index.as_ref(), &third);
+        test_util::assert_not_indexed(index.as_ref(), &second[12]);
+    }
+
+    #[test]
+    fn mempool() {
+        let path = test_util::temp_dir().join("chain.fixture");
+        let blocks = test_util::chain(&[], 0, 2, 1);
+        let low = test_util::hash_transaction(100, [1; 20], MINIMUM_TBPUB_TX_PRICE);
+        let high = test_util::hash_transaction(101, [2; 20], MINIMUM_TBPUB_TX_PRICE + 1);
+        let other = test_util::transaction(102, vec![]);
+        write_fixture(&path, 0, &blocks, &[(low.clone(), 500), (high.clone(), 400), (other.clone(), 300)]);
+        let chain = FixtureChain::new(path.clone());
+        let mempool = Mempool::default();
+        mempool.sync(&chain).unwrap();
+        let entries: Vec<Txid> = mempool.entries().into_iter().map(|(txid, _)| txid).collect();
+        assert_eq!(entries, vec![high.txid(), low.txid()]);
+        assert_eq!(mempool.leader().unwrap().1.fee, 400);
+
+        //Transactions leaving the mempool are evicted and mined ones removed
+        write_fixture(&path, 1, &blocks, &[(low.clone(), 500)]);
+        mempool.sync(&chain).unwrap();
+        assert_eq!(mempool.leader().unwrap().0, low.txid());
+        mempool.remove_confirmed(&test_util::block(blocks[1].block_hash(), 7, vec![low]));
+        assert!(mempool.entries().is_empty());
+    }
+
+    #[test]
+    fn invalid_fixture() {
+        let path = test_util::temp_dir().join("chain.fixture");
+        write(&path, "block 1\n").unwrap();
+        assert!(FixtureChain::new(path).block_count().is_err());
+    }
+}
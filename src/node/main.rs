@@ -3,7 +3,13 @@ use crate::bitcoin_rpc::{get_bitcoin_rpc, send_transaction};
 mod config;
 use crate::config::Config;
 mod database;
-use crate::database::{SettingsDB, HashesDB, RootDIDsDB};
+mod storage;
+use crate::storage::StorageHandle;
+mod memory_storage;
+mod chain_source;
+mod fixture_chain;
+mod block_files;
+mod esplora;
 mod tbpub_transaction;
 use crate::tbpub_transaction::{TBPubTransaction};
 mod error;
@@ -12,12 +18,32 @@ mod cli;
 use crate::cli::JsonRequest;
 mod system;
 use crate::system::{spawn_thread};
+mod scanner;
+use crate::scanner::{Scanner, ScanProgress};
+mod mempool;
+use crate::mempool::Mempool;
+mod zmq;
+mod book;
+use crate::book::{Book, Page, BOOK_HASH_SIZE};
+mod merkle;
+mod book_store;
+use crate::book_store::BookStore;
+mod did;
+mod did_method;
+mod resolver;
+mod peer;
+#[cfg(test)]
+mod test_util;
+use crate::peer::Fetcher;
 
 use bitcoin::Transaction;
+use bitcoin::consensus::deserialize;
 use bitcoincore_rpc::{Auth, Client, RpcApi};
 use serde_json::{Value, json};
 use std::io::{Read, Write};
 use std::net::TcpListener;
+use std::time::Duration;
+use std::sync::mpsc::sync_channel;
 
 use serde_json::from_str as json_from_str;
 use serde_json::to_string as json_to_string;
@@ -25,44 +51,65 @@ use hex::decode as hex_decode;
 use hex::encode as hex_encode;
 
 const MINIMUM_TBPUB_TX_PRICE: u64 = 10000;
+const PRICE_PER_BYTE: u64 = 1;
 const MINIMUM_BLOCK_HEIGHT: u64 = 825478;
 const TBPUB: &str = "5442505542";
 const FLAG_HASH: u8 = 0x00;
 const FLAG_DID: u8 = 0x01;
+const FLAG_METHOD_DID: u8 = 0x02;
+const FLAG_SHA256_HASH: u8 = 0x03;
 const OP_RETURN: u8 = 0x6a;
+const MEMPOOL_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const ZMQ_MEMPOOL_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+const FETCH_INTERVAL: Duration = Duration::from_secs(30);
 
 //#[tokio::main]
 fn main() -> Result<(), Error> {
     let config = Config::new()?;
     println!("[INFO] Config: {:?}", &config);
-    let settings = SettingsDB::new(&config)?;
-    let mut block_height = match settings.get("block_height")? {
+    let storage_handle = StorageHandle::new(&config)?;
+    let storage = storage_handle.open()?;
+    let block_height = match storage.setting(storage::BLOCK_HEIGHT)? {
         Some(bh) => bh.parse::<u64>()?,
         None => {
-            settings.set("block_height", &MINIMUM_BLOCK_HEIGHT.to_string())?; 
+            storage.set_setting(storage::BLOCK_HEIGHT, &MINIMUM_BLOCK_HEIGHT.to_string())?; 
             MINIMUM_BLOCK_HEIGHT
         }
     };
-    let ibs = match settings.get("initial_block_scan")? {
+    let ibs = match storage.setting("initial_block_scan")? {
         Some(ibs) => ibs.parse::<u64>()? != 0,
         None => {
-            settings.set("initial_block_scan", "1")?; 
+            storage.set_setting("initial_block_scan", "1")?; 
             true
         }
     };
     println!("[INFO] Block Height: {}", block_height);
     println!("[INFO] Initial Block Scan: {}", ibs);
 
-    spawn_thread(|config| -> Result<(), Error> {
-        let listener = TcpListener::bind(&config.cliurl.clone())?;
+    //Offline import from the block files of bitcoind, the node exits once
+    //every block in them is indexed.
+    if let Some(datadir) = &config.importblocks {
+        return block_files::import(&config, &storage_handle, datadir, block_height);
+    }
+
+    let mempool = Mempool::default();
+    let progress = ScanProgress::default();
+    let cli_mempool = mempool.clone();
+    let cli_progress = progress.clone();
+    let cli_storage = storage_handle.clone();
+    spawn_thread(move|config| -> Result<(), Error> {
+        let listener = TcpListener::bind(config.cliurl.clone())?;
         for income in listener.incoming() {
             let mut stream = income?; 
+            let mempool = cli_mempool.clone();
+            let progress = cli_progress.clone();
+            let storage = cli_storage.clone();
             spawn_thread(move|config| -> Result<(), Error> {
                 let mut data = String::new();
                 stream.read_to_string(&mut data)?;
                 let request: JsonRequest = json_from_str(&data)?;
-                let response = request.handel_request(&config)?;
-                stream.write(json_to_string(&response)?.as_bytes())?;
+                let response = request.handel_request(&config, &storage, &mempool, &progress)?;
+                stream.write_all(json_to_string(&response)?.as_bytes())?;
                 Ok(())
             }, config.clone());
         }
@@ -70,45 +117,65 @@ fn main() -> Result<(), Error> {
     }, config.clone());
     println!("Started LIPNODE Listener!");
 
-    let rpc = get_bitcoin_rpc(&config)?;
-    let top_block = rpc.get_block_count()?;
-    println!("[INFO] Top Block: {}", top_block);
-    let hashes = HashesDB::new(&config)?;
-    let rootdids = RootDIDsDB::new(&config)?;
-
-    loop {
-        //Scan for blocks
-        if block_height <= top_block {
-            println!("[INFO] Checking block {}", block_height);
-            let block_hash = rpc.get_block_hash(block_height)?;
-            println!("[INFO] Block {} has hash {}", block_height, block_hash);
-            let block = rpc.get_block(&block_hash)?;
-            let mut tbpub_tx: Option<TBPubTransaction> = None;
-            for tx in block.txdata {
-                match TBPubTransaction::from_transaction(&tx) {
-                    Some(tmp_tx) => {
-                        match tbpub_tx {
-                            Some(stored_tx) if stored_tx.price < tmp_tx.price => tbpub_tx = Some(tmp_tx),
-                            None => tbpub_tx = Some(tmp_tx),
-                            _ => continue
-                        }
-                    },
-                    None => continue
-                };
+    let peer_storage = storage_handle.clone();
+    spawn_thread(move|config| peer::serve(&config, &peer_storage), config.clone());
+    let fetcher_storage = storage_handle.clone();
+    spawn_thread(move|config| -> Result<(), Error> {
+        let mut fetcher = Fetcher::new(&config, &fetcher_storage)?;
+        //Books that failed to fetch are tried again at the next interval
+        loop {
+            if let Err(error) = fetcher.fetch_pending() {
+                println!("[ERROR] Could not fetch pending books: {}", error);
             }
-            if tbpub_tx.is_some() {
-                let top_tbpub_tx = tbpub_tx.unwrap();
-                if top_tbpub_tx.is_hash {
-                    hashes.add(&top_tbpub_tx.data, block_height, top_tbpub_tx.price)?;
-                } else {
-                    rootdids.add(&top_tbpub_tx.data, block_height, top_tbpub_tx.price)?;
+            std::thread::sleep(FETCH_INTERVAL);
+        }
+    }, config.clone());
+
+    //With rawtx notifications the sync only has to catch evictions.
+    let mempool_sync_interval = match config.zmqpubrawtx {
+        Some(_) => ZMQ_MEMPOOL_SYNC_INTERVAL,
+        None => MEMPOOL_SYNC_INTERVAL
+    };
+    let chain = chain_source::open(&config)?;
+    match chain.tracks_mempool() {
+        true => {
+            let sync_mempool = mempool.clone();
+            spawn_thread(move|config| -> Result<(), Error> {
+                let chain = chain_source::open(&config)?;
+                //A failed sync is tried again at the next interval
+                loop {
+                    if let Err(error) = sync_mempool.sync(chain.as_ref()) {
+                        println!("[ERROR] Could not sync the mempool: {}", error);
+                    }
+                    std::thread::sleep(mempool_sync_interval);
                 }
+            }, config.clone());
+        },
+        false => println!("[INFO] The mempool is not tracked with this chain source")
+    }
+
+    let mut scanner = Scanner::new(&config, &storage_handle, block_height, mempool.clone(), progress)?;
+    scanner.backfill(chain.as_ref())?;
+
+    //Block notifications only wake the scanner, so one pending is enough.
+    let block_notifications = config.zmqpubhashblock.as_ref().map(|endpoint| {
+        let (block_sender, block_receiver) = sync_channel::<()>(1);
+        zmq::subscribe(endpoint.clone(), "hashblock", move|_| {let _ = block_sender.try_send(());});
+        block_receiver
+    });
+    if let Some(endpoint) = &config.zmqpubrawtx {
+        let mempool = mempool.clone();
+        let zmq_chain = chain_source::open(&config)?;
+        zmq::subscribe(endpoint.clone(), "rawtx", move|message| {
+            let result = match message.get(1).map(|raw_tx| deserialize::<Transaction>(raw_tx)) {
+                Some(Ok(tx)) => mempool.add_transaction(zmq_chain.as_ref(), &tx),
+                Some(Err(error)) => Err(Error::from(error)),
+                None => Ok(())
+            };
+            if let Err(error) = result {
+                println!("[ERROR] Could not handle rawtx notification: {}", error);
             }
-            block_height += 1;
-            settings.set("block_height", &block_height.to_string())?;
-        } else {
-            //Waiting for next block and Initial Block Scan is finished.
-            settings.set("initial_block_scan", "0")?;
-        }
+        });
     }
+    scanner.follow(chain.as_ref(), block_notifications)
 }
@@ -0,0 +1,191 @@
+use crate::Error;
+use crate::database::{HashRecord, HashStatus, RootDIDRecord, LoserRecord, DIDDocument};
+use crate::storage::{Storage, Record, IndexedBlock, BLOCK_HEIGHT};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct Tables {
+    settings: HashMap<String, String>,
+    hashes: HashMap<String, HashRecord>,
+    rootdids: HashMap<String, RootDIDRecord>,
+    blocks: BTreeMap<u64, String>,
+    losers: HashMap<String, LoserRecord>,
+    //(hash, did) of every book shared
+    shares: BTreeSet<(String, String)>,
+    diddocuments: HashMap<String, DIDDocument>,
+}
+
+impl Tables {
+    fn add_record(&mut self, record: &Record) {
+        match record {
+            Record::Hash(record) => match self.hashes.get_mut(&record.hash) {
+                Some(stored) => if stored.location.is_none() {stored.location = record.location.clone()},
+                None => {self.hashes.insert(record.hash.clone(), record.clone());}
+            },
+            Record::RootDID(record) => match self.rootdids.get_mut(&record.did) {
+                Some(stored) => if stored.location.is_none() {stored.location = record.location.clone()},
+                None => {self.rootdids.insert(record.did.clone(), record.clone());}
+            }
+        }
+    }
+}
+
+//Keeps everything in memory behind a lock, clones share the same tables so
+//every thread of the node sees the same index. Nothing survives a restart.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    tables: Arc<Mutex<Tables>>,
+}
+
+impl std::fmt::Debug for MemoryStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MemoryStorage")
+    }
+}
+
+impl MemoryStorage {
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        //Every change is made in one go so a poisoned lock still holds whole records
+        self.tables.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn setting(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(self.tables().settings.get(key).cloned())
+    }
+
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.tables().settings.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn hash(&self, hash: &str) -> Result<Option<HashRecord>, Error> {
+        Ok(self.tables().hashes.get(hash).cloned())
+    }
+
+    fn hash_by_txid(&self, txid: &str) -> Result<Option<HashRecord>, Error> {
+        Ok(self.tables().hashes.values()
+            .find(|record| record.location.as_ref().is_some_and(|location| location.txid == txid))
+            .cloned())
+    }
+
+    fn pending_hashes(&self) -> Result<Vec<HashRecord>, Error> {
+        let mut records: Vec<HashRecord> = self.tables().hashes.values()
+            .filter(|record| record.status == HashStatus::Pending)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| record.block_height);
+        Ok(records)
+    }
+
+    fn set_hash_status(&self, hash: &str, status: HashStatus) -> Result<(), Error> {
+        if let Some(record) = self.tables().hashes.get_mut(hash) {
+            record.status = status;
+        }
+        Ok(())
+    }
+
+    fn did(&self, did: &str) -> Result<Option<RootDIDRecord>, Error> {
+        Ok(self.tables().rootdids.get(did).cloned())
+    }
+
+    fn did_by_txid(&self, txid: &str) -> Result<Option<RootDIDRecord>, Error> {
+        Ok(self.tables().rootdids.values()
+            .find(|record| record.location.as_ref().is_some_and(|location| location.txid == txid))
+            .cloned())
+    }
+
+    fn dids(&self) -> Result<Vec<String>, Error> {
+        let mut records: Vec<(u64, String)> = self.tables().rootdids.values()
+            .map(|record| (record.block_height, record.did.clone()))
+            .collect();
+        records.sort();
+        Ok(records.into_iter().map(|(_, did)| did).collect())
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<Option<String>, Error> {
+        Ok(self.tables().blocks.get(&block_height).cloned())
+    }
+
+    fn losers(&self, block_height: u64) -> Result<Vec<LoserRecord>, Error> {
+        let mut records: Vec<LoserRecord> = self.tables().losers.values()
+            .filter(|record| record.block_height == block_height)
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| b.price.cmp(&a.price).then_with(|| a.txid.cmp(&b.txid)));
+        Ok(records)
+    }
+
+    fn missing_locations(&self) -> Result<Vec<u64>, Error> {
+        let tables = self.tables();
+        let mut heights: Vec<u64> = tables.hashes.values()
+            .filter(|record| record.location.is_none())
+            .map(|record| record.block_height)
+            .chain(tables.rootdids.values()
+                .filter(|record| record.location.is_none())
+                .map(|record| record.block_height))
+            .collect();
+        heights.sort();
+        heights.dedup();
+        Ok(heights)
+    }
+
+    fn add_record(&self, record: &Record) -> Result<(), Error> {
+        self.tables().add_record(record);
+        Ok(())
+    }
+
+    fn add_blocks(&self, blocks: &[IndexedBlock]) -> Result<(), Error> {
+        let mut tables = self.tables();
+        for block in blocks {
+            for loser in &block.losers {
+                tables.losers.insert(loser.txid.clone(), loser.clone());
+            }
+            if let Some(winner) = &block.winner {
+                tables.add_record(winner);
+            }
+            tables.blocks.insert(block.block_height, block.block_hash.clone());
+            tables.settings.insert(BLOCK_HEIGHT.to_string(), (block.block_height + 1).to_string());
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, fork_point: u64) -> Result<(), Error> {
+        let mut tables = self.tables();
+        tables.hashes.retain(|_, record| record.block_height <= fork_point);
+        tables.rootdids.retain(|_, record| record.block_height <= fork_point);
+        tables.blocks.retain(|block_height, _| *block_height <= fork_point);
+        tables.losers.retain(|_, record| record.block_height <= fork_point);
+        tables.settings.insert(BLOCK_HEIGHT.to_string(), (fork_point + 1).to_string());
+        Ok(())
+    }
+
+    fn add_share(&self, hash: &str, did: &str) -> Result<(), Error> {
+        self.tables().shares.insert((hash.to_string(), did.to_string()));
+        Ok(())
+    }
+
+    fn has_share(&self, hash: &str, did: &str) -> Result<bool, Error> {
+        Ok(self.tables().shares.contains(&(hash.to_string(), did.to_string())))
+    }
+
+    fn share_stats(&self) -> Result<Vec<(String, u64)>, Error> {
+        let mut stats: BTreeMap<String, u64> = BTreeMap::new();
+        for (_, did) in &self.tables().shares {
+            *stats.entry(did.clone()).or_default() += 1;
+        }
+        Ok(stats.into_iter().collect())
+    }
+
+    fn did_document(&self, did: &str) -> Result<Option<DIDDocument>, Error> {
+        Ok(self.tables().diddocuments.get(did).cloned())
+    }
+
+    fn set_did_document(&self, did: &str, document: &DIDDocument) -> Result<(), Error> {
+        self.tables().diddocuments.insert(did.to_string(), document.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{Error, TBPubTransaction};
+use crate::chain_source::ChainSource;
+use crate::tbpub_transaction::rank;
+use crate::{Value, json};
+
+use bitcoin::{Block, Transaction, Txid};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: TBPubTransaction,
+    pub fee: u64,
+    pub vsize: u64,
+}
+
+impl MempoolEntry {
+    pub fn feerate(&self) -> f64 {
+        self.fee as f64 / self.vsize as f64
+    }
+
+    pub fn to_json(&self, txid: &Txid) -> Value {
+        let mut result: Value = json!(null);
+        result["txid"] = json!(txid.to_string());
+        result["price"] = json!(self.tx.price);
+        result["fee"] = json!(self.fee);
+        result["vsize"] = json!(self.vsize);
+        result["feerate"] = json!(self.feerate());
+        result["is_hash"] = json!(self.tx.is_hash);
+        result["data"] = json!(self.tx.data);
+        result
+    }
+}
+
+#[derive(Default)]
+struct MempoolState {
+    entries: HashMap<Txid, MempoolEntry>,
+    //Mempool transactions already looked at that are not TBPUB Transactions
+    ignored: HashSet<Txid>,
+}
+
+//Index of the TBPUB Transactions waiting in the mempool, shared between the
+//mempool sync thread, the ZMQ subscription, the scanner and the cli.
+#[derive(Clone, Default)]
+pub struct Mempool {
+    state: Arc<Mutex<MempoolState>>,
+}
+
+impl Mempool {
+    //Adds the transaction if it is a TBPUB Transaction still in the mempool.
+    pub fn add_transaction(&self, chain: &dyn ChainSource, tx: &Transaction) -> Result<(), Error> {
+        let txid = tx.txid();
+        let tbpub_tx = match TBPubTransaction::from_transaction(tx) {
+            Some(tbpub_tx) => tbpub_tx,
+            None => {
+                self.state.lock().unwrap().ignored.insert(txid);
+                return Ok(());
+            }
+        };
+        //Transactions announced as part of a block are no longer in the mempool
+        let (fee, vsize) = match chain.mempool_entry(&txid)? {
+            Some(mempool_entry) => mempool_entry,
+            None => return Ok(())
+        };
+        let entry = MempoolEntry{tx: tbpub_tx, fee, vsize};
+        if self.state.lock().unwrap().entries.insert(txid, entry).is_none() {
+            println!("[INFO] TBPUB Transaction {} entered the mempool", txid);
+        }
+        Ok(())
+    }
+
+    pub fn add_txid(&self, chain: &dyn ChainSource, txid: &Txid) -> Result<(), Error> {
+        match chain.transaction(txid)? {
+            Some(tx) => self.add_transaction(chain, &tx),
+            None => Ok(())
+        }
+    }
+
+    //Evicts transactions that left the mempool and looks at any new ones.
+    pub fn sync(&self, chain: &dyn ChainSource) -> Result<(), Error> {
+        let txids: HashSet<Txid> = chain.mempool()?.into_iter().collect();
+        let new_txids: Vec<Txid> = {
+            let mut state = self.state.lock().unwrap();
+            state.entries.retain(|txid, _| {
+                let keep = txids.contains(txid);
+                if !keep {println!("[INFO] TBPUB Transaction {} left the mempool", txid);}
+                keep
+            });
+            state.ignored.retain(|txid| txids.contains(txid));
+            txids.iter()
+                .filter(|&txid| !state.entries.contains_key(txid) && !state.ignored.contains(txid))
+                .cloned()
+                .collect()
+        };
+        for txid in new_txids {
+            self.add_txid(chain, &txid)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_confirmed(&self, block: &Block) {
+        let mut state = self.state.lock().unwrap();
+        for tx in &block.txdata {
+            if state.entries.remove(&tx.txid()).is_some() {
+                println!("[INFO] TBPUB Transaction {} confirmed", tx.txid());
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(Txid, MempoolEntry)> {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<(Txid, MempoolEntry)> = state.entries.iter()
+            .map(|(txid, entry)| (*txid, entry.clone()))
+            .collect();
+        //Ranked the same way blocks are decided
+        entries.sort_by(|(a_txid, a), (b_txid, b)| rank((a_txid, a.tx.price), (b_txid, b.tx.price)));
+        entries
+    }
+
+    //The TBPUB Transaction that would win the block if it was mined now.
+    pub fn leader(&self) -> Option<(Txid, MempoolEntry)> {
+        self.entries().into_iter().next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, MockChain};
+    use crate::MINIMUM_TBPUB_TX_PRICE;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, ScriptBuf, TxOut};
+
+    fn hash_transaction(seed: u32, price: u64) -> Transaction {
+        test_util::hash_transaction(seed, [seed as u8; 20], price)
+    }
+
+    #[test]
+    fn add_transaction() {
+        let chain = MockChain::new(vec![]);
+        let mempool = Mempool::default();
+        let tx = hash_transaction(1, MINIMUM_TBPUB_TX_PRICE);
+        //Not in the mempool of the chain, eg announced as part of a block
+        mempool.add_transaction(&chain, &tx).unwrap();
+        assert!(mempool.entries().is_empty());
+
+        chain.add_to_mempool(tx.clone(), 500);
+        mempool.add_transaction(&chain, &tx).unwrap();
+        let entries = mempool.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, tx.txid());
+        assert_eq!((entries[0].1.fee, entries[0].1.vsize), (500, tx.vsize() as u64));
+        assert_eq!(entries[0].1.tx.price, MINIMUM_TBPUB_TX_PRICE);
+
+        //Transactions that are not TBPUB Transactions are remembered and skipped
+        let other = test_util::transaction(2, vec![TxOut{value: 1000, script_pubkey: ScriptBuf::new()}]);
+        chain.add_to_mempool(other.clone(), 500);
+        mempool.add_txid(&chain, &other.txid()).unwrap();
+        assert_eq!(mempool.entries().len(), 1);
+        assert!(mempool.state.lock().unwrap().ignored.contains(&other.txid()));
+    }
+
+    //A sync evicts what left the mempool of the chain and adds what is new.
+    #[test]
+    fn sync_evicts() {
+        let (first, second) = (hash_transaction(1, MINIMUM_TBPUB_TX_PRICE), hash_transaction(2, MINIMUM_TBPUB_TX_PRICE));
+        let chain = MockChain::new(vec![]);
+        chain.add_to_mempool(first.clone(), 500);
+        let mempool = Mempool::default();
+        mempool.sync(&chain).unwrap();
+        assert_eq!(mempool.leader().unwrap().0, first.txid());
+
+        let chain = MockChain::new(vec![]);
+        chain.add_to_mempool(second.clone(), 500);
+        mempool.sync(&chain).unwrap();
+        let txids: Vec<Txid> = mempool.entries().into_iter().map(|(txid, _)| txid).collect();
+        assert_eq!(txids, vec![second.txid()]);
+    }
+
+    #[test]
+    fn remove_confirmed() {
+        let (mined, waiting) = (hash_transaction(1, MINIMUM_TBPUB_TX_PRICE), hash_transaction(2, MINIMUM_TBPUB_TX_PRICE));
+        let chain = MockChain::new(vec![]);
+        let mempool = Mempool::default();
+        for tx in [&mined, &waiting] {
+            chain.add_to_mempool(tx.clone(), 500);
+            mempool.add_transaction(&chain, tx).unwrap();
+        }
+        mempool.remove_confirmed(&test_util::block(BlockHash::all_zeros(), 1, vec![mined]));
+        let txids: Vec<Txid> = mempool.entries().into_iter().map(|(txid, _)| txid).collect();
+        assert_eq!(txids, vec![waiting.txid()]);
+    }
+
+    //The leader is decided like a block, the highest price and then the
+    //lowest txid, whatever the fee.
+    #[test]
+    fn leader() {
+        let chain = MockChain::new(vec![]);
+        let mempool = Mempool::default();
+        assert!(mempool.leader().is_none());
+        let txs: Vec<Transaction> = (1..=4).map(|seed| hash_transaction(seed, MINIMUM_TBPUB_TX_PRICE + seed as u64 % 2)).collect();
+        for (fee, tx) in (0..).zip(&txs) {
+            chain.add_to_mempool(tx.clone(), 10000 - fee * 1000);
+            mempool.add_transaction(&chain, tx).unwrap();
+        }
+        let mut expected: Vec<Txid> = txs.iter().filter(|tx| tx.output[0].value > MINIMUM_TBPUB_TX_PRICE).map(|tx| tx.txid()).collect();
+        let mut cheaper: Vec<Txid> = txs.iter().filter(|tx| tx.output[0].value == MINIMUM_TBPUB_TX_PRICE).map(|tx| tx.txid()).collect();
+        expected.sort_by_key(|txid| txid.to_string());
+        cheaper.sort_by_key(|txid| txid.to_string());
+        expected.extend(cheaper);
+        let ranked: Vec<Txid> = mempool.entries().into_iter().map(|(txid, _)| txid).collect();
+        assert_eq!(ranked, expected);
+        assert_eq!(mempool.leader().unwrap().0, expected[0]);
+    }
+}
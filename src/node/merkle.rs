@@ -0,0 +1,181 @@
+use crate::Error;
+use crate::book::BOOK_HASH_SIZE;
+
+use merkletree::hash::Algorithm;
+use merkletree::merkle::{Element, MerkleTree};
+use merkletree::proof::Proof;
+use merkletree::store::VecStore;
+use serde::{Serialize, Deserialize};
+use sha1::{Sha1, Digest};
+use std::hash::Hasher;
+use typenum::{U0, U2};
+
+//Node of a book's Merkle tree, leaves are the SHA-1 of the canonical
+//encoding of a page, which merkletree hashes again with its leaf prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MerkleHash(pub [u8; BOOK_HASH_SIZE]);
+
+impl AsRef<[u8]> for MerkleHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Element for MerkleHash {
+    fn byte_len() -> usize {
+        BOOK_HASH_SIZE
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        MerkleHash(bytes.try_into().unwrap())
+    }
+
+    fn copy_to_slice(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0);
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Sha1Algorithm(Sha1);
+
+impl Hasher for Sha1Algorithm {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        u64::from_le_bytes(self.0.clone().finalize()[..8].try_into().unwrap())
+    }
+}
+
+impl Algorithm<MerkleHash> for Sha1Algorithm {
+    fn hash(&mut self) -> MerkleHash {
+        MerkleHash(self.0.finalize_reset().into())
+    }
+}
+
+type BookTree = MerkleTree<MerkleHash, Sha1Algorithm, VecStore<MerkleHash>>;
+
+pub fn page_digest(encoded_page: &[u8]) -> MerkleHash {
+    MerkleHash(Sha1::digest(encoded_page).into())
+}
+
+fn leaf_hash(encoded_page: &[u8]) -> MerkleHash {
+    Sha1Algorithm::default().leaf(page_digest(encoded_page))
+}
+
+//The tree is padded with empty leaves up to a power of two, merkletree
+//requires at least two leaves.
+fn build_tree(leaves: Vec<MerkleHash>) -> Result<BookTree, Error> {
+    let size = std::cmp::max(leaves.len().next_power_of_two(), 2);
+    let mut leaves = leaves;
+    leaves.resize(size, MerkleHash::default());
+    BookTree::new(leaves).map_err(Error::MerkleTreeError)
+}
+
+pub fn root(leaves: Vec<MerkleHash>) -> Result<[u8; BOOK_HASH_SIZE], Error> {
+    Ok(build_tree(leaves)?.root().0)
+}
+
+pub fn proof(leaves: Vec<MerkleHash>, index: usize) -> Result<PageProof, Error> {
+    if index >= leaves.len() {return Err(Error::InvalidBook(format!("Page {} does not exist", index)));}
+    let proof = build_tree(leaves)?.gen_proof(index).map_err(Error::MerkleTreeError)?;
+    Ok(PageProof{
+        index,
+        lemma: proof.lemma().iter().map(|node| hex::encode(node.0)).collect(),
+        path: proof.path().clone(),
+    })
+}
+
+//Inclusion proof for a single page, the lemma starts with the page's leaf
+//and ends with the root, the path holds the side taken at every level.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PageProof {
+    pub index: usize,
+    pub lemma: Vec<String>,
+    pub path: Vec<usize>,
+}
+
+impl PageProof {
+    pub fn verify(&self, root: &[u8; BOOK_HASH_SIZE], encoded_page: &[u8]) -> Result<bool, Error> {
+        let lemma = self.lemma.iter()
+            .map(|node| Ok(MerkleHash(hex::decode(node)?.as_slice().try_into()?)))
+            .collect::<Result<Vec<MerkleHash>, Error>>()?;
+        if lemma.first() != Some(&leaf_hash(encoded_page)) || lemma.last() != Some(&MerkleHash(*root)) {
+            return Ok(false);
+        }
+        let index = self.path.iter().enumerate().fold(0, |index, (level, side)| index | (side << level));
+        if index != self.index {return Ok(false);}
+        Proof::<MerkleHash, U2>::new::<U0, U0>(None, lemma, self.path.clone())
+            .and_then(|proof| proof.validate::<Sha1Algorithm>())
+            .map_err(Error::MerkleTreeError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Book, Page, PRICE_PER_BYTE};
+
+    fn pages(count: usize) -> Book {
+        Book{pages: (0..count).map(|index| {
+            let data = format!("page {}", index);
+            Page{price: data.len() as u64 * PRICE_PER_BYTE, data}
+        }).collect()}
+    }
+
+    fn verifies(proof: &PageProof, root: &[u8; BOOK_HASH_SIZE], page: &Page) -> bool {
+        proof.verify(root, &page.encode()).unwrap_or(false)
+    }
+
+    //Every page of books of one leaf, a power of two and padded sizes.
+    #[test]
+    fn every_page_verifies() {
+        for count in [1, 2, 3, 4, 5, 8] {
+            let book = pages(count);
+            let root = book.merkle_root().unwrap();
+            for (index, page) in book.pages.iter().enumerate() {
+                let proof = book.page_proof(index).unwrap();
+                assert_eq!(proof.index, index);
+                assert!(verifies(&proof, &root, page), "page {} of {}", index, count);
+            }
+            assert!(book.page_proof(count).is_err());
+        }
+    }
+
+    #[test]
+    fn single_page() {
+        let book = pages(1);
+        let root = book.merkle_root().unwrap();
+        //The one page is padded with an empty leaf, the root is not the page
+        assert_ne!(MerkleHash(root), leaf_hash(&book.pages[0].encode()));
+        assert_ne!(root, book.hash());
+        assert!(verifies(&book.page_proof(0).unwrap(), &root, &book.pages[0]));
+    }
+
+    #[test]
+    fn tampered_proofs() {
+        let book = pages(5);
+        let root = book.merkle_root().unwrap();
+        let proof = book.page_proof(2).unwrap();
+        assert!(!verifies(&proof, &root, &book.pages[3]));
+        assert!(!verifies(&proof, &pages(6).merkle_root().unwrap(), &book.pages[2]));
+
+        let mut lemma = proof.clone();
+        lemma.lemma[1] = hex::encode([0u8; BOOK_HASH_SIZE]);
+        assert!(!verifies(&lemma, &root, &book.pages[2]));
+        let mut short = proof.clone();
+        short.lemma.remove(1);
+        assert!(!verifies(&short, &root, &book.pages[2]));
+
+        let mut path = proof.clone();
+        path.path[0] ^= 1;
+        assert!(!verifies(&path, &root, &book.pages[2]));
+
+        let mut index = proof.clone();
+        index.index = 3;
+        assert!(!verifies(&index, &root, &book.pages[2]));
+        //The proof of another page does not prove this one
+        assert!(!verifies(&book.page_proof(3).unwrap(), &root, &book.pages[2]));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::{Error, Config, Book, BookStore};
+use crate::storage::{Storage, StorageHandle};
+use crate::database::HashRecord;
+use crate::{hex_encode, hex_decode, PRICE_PER_BYTE};
+use crate::did::{self, Identity};
+use crate::resolver::Resolver;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tiny_http::{Method, Request, Response, Server};
+
+//Root Nodes resolve book hashes by asking each other over HTTP:
+//GET /challenge answers with a random hex encoded challenge.
+//GET /book/<hash> answers with the canonical encoding of the book. Requests
+//name the Root Node asking in the DID_HEADER and prove they own that DID by
+//signing the challenge followed by the hash with the key of the DID. Only
+//Root Nodes published on chain are served and never the same book twice.
+pub const BOOK_PATH: &str = "/book/";
+pub const CHALLENGE_PATH: &str = "/challenge";
+pub const DID_HEADER: &str = "X-TBPUB-DID";
+pub const CHALLENGE_HEADER: &str = "X-TBPUB-Challenge";
+pub const SIGNATURE_HEADER: &str = "X-TBPUB-Signature";
+const CHALLENGE_SIZE: usize = 32;
+const CHALLENGE_EXPIRY: Duration = Duration::from_secs(60);
+const MAXIMUM_CHALLENGES: usize = 10000;
+const MAXIMUM_REQUESTS: usize = 64;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+//A peer that could not give us a book is asked for it again after this long,
+//doubled after every failure up to the maximum
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAXIMUM_RETRY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn challenge_message(challenge: &str, hash: &str) -> Vec<u8> {
+    format!("{}{}", challenge, hash).into_bytes()
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request.headers().iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+type PeerResponse = Response<Cursor<Vec<u8>>>;
+
+fn respond(status: u16, body: Vec<u8>) -> PeerResponse {
+    Response::from_data(body).with_status_code(status)
+}
+
+//Shared by the threads answering requests, each opens its own storage.
+#[derive(Clone)]
+struct PeerServer {
+    config: Config,
+    storage: StorageHandle,
+    //Challenges handed out and when they were, each can only be used once
+    challenges: Arc<Mutex<HashMap<String, Instant>>>,
+    //(hash, DID) of the books being sent right now, so two requests at once
+    //can not both get a book that may only be shared once
+    sending: Arc<Mutex<HashSet<(String, String)>>>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl PeerServer {
+    fn new_challenge(&self) -> Result<PeerResponse, Error> {
+        let mut challenges = self.challenges.lock().unwrap();
+        if challenges.len() >= MAXIMUM_CHALLENGES {
+            challenges.retain(|_, issued| issued.elapsed() < CHALLENGE_EXPIRY);
+            if challenges.len() >= MAXIMUM_CHALLENGES {return Ok(respond(503, vec![]));}
+        }
+        let mut challenge = [0u8; CHALLENGE_SIZE];
+        getrandom::getrandom(&mut challenge).map_err(std::io::Error::from)?;
+        let challenge = hex_encode(challenge);
+        challenges.insert(challenge.clone(), Instant::now());
+        Ok(respond(200, challenge.into_bytes()))
+    }
+
+    //The hex encoded DID of the peer if it is published on chain and signed a
+    //challenge we handed out with the key of the DID.
+    fn authenticate(&self, storage: &dyn Storage, request: &Request, hash: &str) -> Result<Option<String>, Error> {
+        let (did, hex_did, challenge, signature) = match (header(request, DID_HEADER),
+                header(request, CHALLENGE_HEADER), header(request, SIGNATURE_HEADER)) {
+            (Some(did), Some(challenge), Some(signature)) => (did, hex_encode(did), challenge, signature),
+            _ => return Ok(None)
+        };
+        match self.challenges.lock().unwrap().remove(challenge) {
+            Some(issued) if issued.elapsed() < CHALLENGE_EXPIRY => (),
+            _ => return Ok(None)
+        }
+        //DIDs whose key is not embedded in them have it resolved
+        let public_key = match storage.did(&hex_did)? {
+            Some(record) => match record.public_key {
+                Some(public_key) => Some(hex_decode(public_key)?),
+                None => Resolver::new(&self.config, &self.storage)?.public_key(did)?
+            },
+            None => None
+        };
+        let public_key = match public_key {
+            Some(public_key) => public_key,
+            None => return Ok(None)
+        };
+        let signature = hex_decode(signature).unwrap_or_default();
+        match did::verify(&public_key, &challenge_message(challenge, hash), &signature) {
+            true => Ok(Some(hex_did)),
+            false => Ok(None)
+        }
+    }
+
+    //Returns the response and, when a book is sent, the hash and hex encoded
+    //DID of the peer it is being sent to.
+    fn handle_request(&self, storage: &dyn Storage, request: &Request) -> Result<(PeerResponse, Option<(String, String)>), Error> {
+        if *request.method() != Method::Get {return Ok((respond(405, vec![]), None));}
+        if request.url() == CHALLENGE_PATH {return Ok((self.new_challenge()?, None));}
+        let hash = match request.url().strip_prefix(BOOK_PATH) {
+            Some(hash) => hash.to_lowercase(),
+            None => return Ok((respond(404, vec![]), None))
+        };
+        let did = match self.authenticate(storage, request, &hash)? {
+            Some(did) => did,
+            None => return Ok((respond(401, vec![]), None))
+        };
+        let book = match self.storage.books()?.get(&hash) {
+            Ok(Some(book)) => book,
+            Ok(None) => return Ok((respond(404, vec![]), None)),
+            Err(_) => return Ok((respond(400, vec![]), None))
+        };
+        let share = (hash, did);
+        if !self.sending.lock().unwrap().insert(share.clone()) {return Ok((respond(403, vec![]), None));}
+        let shared = storage.has_share(&share.0, &share.1);
+        if !matches!(shared, Ok(false)) {
+            self.sending.lock().unwrap().remove(&share);
+            shared?;
+            return Ok((respond(403, vec![]), None));
+        }
+        Ok((respond(200, book.encode()), Some(share)))
+    }
+
+    //Internal errors are answered with 500 and logged, they only end this
+    //request and never the node.
+    fn answer(&self, request: Request) {
+        let url = request.url().to_string();
+        let answer = self.storage.open().and_then(|storage| {
+            let answer = self.handle_request(storage.as_ref(), &request)?;
+            Ok((answer, storage))
+        });
+        let ((response, share), storage) = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                println!("[ERROR] Could not answer peer request {}: {}", url, e);
+                let _ = request.respond(respond(500, vec![]));
+                return;
+            }
+        };
+        let sent = request.respond(response).is_ok();
+        if let Some((hash, did)) = &share {
+            //Only count a share once the book was actually sent
+            if sent {
+                if let Err(e) = storage.add_share(hash, did) {
+                    println!("[ERROR] Could not record sharing book {} with {}: {}", hash, did, e);
+                }
+            }
+            self.sending.lock().unwrap().remove(&(hash.clone(), did.clone()));
+        }
+    }
+}
+
+//Serves stored books to other Root Nodes on peerurl.
+pub fn serve(config: &Config, storage: &StorageHandle) -> Result<(), Error> {
+    let server = Server::http(&config.peerurl).map_err(|e| Error::PeerProtocol(e.to_string()))?;
+    println!("[INFO] Serving books on {}", config.peerurl);
+    serve_requests(server, config, storage);
+    Ok(())
+}
+
+//Every request is answered on a thread of its own so a slow peer or DID
+//resolution does not hold up the others, up to MAXIMUM_REQUESTS at once.
+fn serve_requests(server: Server, config: &Config, storage: &StorageHandle) {
+    let peer_server = PeerServer{
+        config: config.clone(),
+        storage: storage.clone(),
+        challenges: Arc::new(Mutex::new(HashMap::new())),
+        sending: Arc::new(Mutex::new(HashSet::new())),
+        requests: Arc::new(AtomicUsize::new(0)),
+    };
+    for request in server.incoming_requests() {
+        if peer_server.requests.fetch_add(1, Ordering::SeqCst) >= MAXIMUM_REQUESTS {
+            peer_server.requests.fetch_sub(1, Ordering::SeqCst);
+            let _ = request.respond(respond(503, vec![]));
+            continue;
+        }
+        let peer_server = peer_server.clone();
+        std::thread::spawn(move|| {
+            peer_server.answer(request);
+            peer_server.requests.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn with_scheme(url: &str) -> String {
+    match url.contains("://") {
+        true => url.to_string(),
+        false => format!("http://{}", url)
+    }
+}
+
+//The url a Root Node serves books on, configured with addpeer=<did>@<url>.
+pub fn configured_endpoint(config: &Config, did: &str) -> Option<String> {
+    config.peers.iter()
+        .find(|(peer_did, _)| peer_did == did)
+        .map(|(_, url)| with_scheme(url))
+}
+
+//The status of an HTTP error answer.
+fn http_status(error: &Error) -> Option<u16> {
+    match error {
+        Error::HTTPError(error) => match error.as_ref() {
+            ureq::Error::Status(status, _) => Some(*status),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+//When a peer may be asked for a book again after failing to give it to us,
+//None once it refused because it already shared the book with us.
+struct Retry {
+    failures: u32,
+    at: Option<Instant>,
+}
+
+impl Retry {
+    fn due(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+pub struct Fetcher {
+    config: Config,
+    storage: Box<dyn Storage>,
+    books: BookStore,
+    identity: Identity,
+    resolver: Resolver,
+    //Keyed by (hash, DID of the peer)
+    retries: HashMap<(String, String), Retry>,
+}
+
+impl Fetcher {
+    pub fn new(config: &Config, storage: &StorageHandle) -> Result<Fetcher, Error> {
+        Ok(Fetcher{
+            config: config.clone(),
+            storage: storage.open()?,
+            books: storage.books()?,
+            identity: storage.identity()?,
+            resolver: Resolver::new(config, storage)?,
+            retries: HashMap::new(),
+        })
+    }
+
+    //Root Nodes published on chain that we know how to reach, either from
+    //addpeer or from the service endpoint in their DID document.
+    fn peers(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut peers = Vec::new();
+        for did in self.storage.dids()? {
+            let did = String::from_utf8_lossy(&hex_decode(did)?).to_string();
+            if did == self.identity.did() {continue;}
+            let url = match configured_endpoint(&self.config, &did) {
+                Some(url) => Some(url),
+                None => self.resolver.resolve_endpoint(&did)?.map(|url| with_scheme(&url))
+            };
+            if let Some(url) = url {
+                peers.push((did, url));
+            }
+        }
+        Ok(peers)
+    }
+
+    //Asks every known Root Node in turn for each pending book until one
+    //answers with a book matching the hash. Peers that failed to are backed
+    //off from and peers that refused are not asked for that book again.
+    pub fn fetch_pending(&mut self) -> Result<(), Error> {
+        let pending = self.storage.pending_hashes()?;
+        let hashes: HashSet<&str> = pending.iter().map(|record| record.hash.as_str()).collect();
+        self.retries.retain(|(hash, _), _| hashes.contains(hash.as_str()));
+        if pending.is_empty() {return Ok(());}
+        let peers = self.peers()?;
+        for record in &pending {
+            for (did, url) in &peers {
+                let key = (record.hash.clone(), did.clone());
+                if self.retries.get(&key).is_some_and(|retry| !retry.due()) {continue;}
+                match self.fetch(url, record) {
+                    Ok(book) => {
+                        self.books.insert(&record.hash, &book)?;
+                        let status = self.storage.resolve_hash(&record.hash, book.size())?;
+                        println!("[INFO] Resolved book {} from {} as {}", record.hash, did,
+                            status.map(|status| status.as_str().to_string()).unwrap_or_default());
+                        break;
+                    },
+                    Err(e) => {
+                        println!("[ERROR] Could not fetch book {} from {}: {}", record.hash, did, e);
+                        let retry = self.retries.entry(key).or_insert(Retry{failures: 0, at: None});
+                        retry.failures += 1;
+                        //Peers only share a book with us once
+                        retry.at = match http_status(&e) {
+                            Some(403) => None,
+                            _ => Some(Instant::now() + std::cmp::min(
+                                RETRY_INTERVAL.saturating_mul(1 << (retry.failures - 1).min(16)), MAXIMUM_RETRY_INTERVAL))
+                        };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Peers sending more than the hash paid for fail like any other, what they
+    //sent was never checked against the hash so it settles nothing.
+    fn fetch(&self, url: &str, record: &HashRecord) -> Result<Book, Error> {
+        let challenge = ureq::get(&format!("{}{}", url, CHALLENGE_PATH))
+            .timeout(FETCH_TIMEOUT)
+            .call()
+            .map_err(Box::new)?
+            .into_string()?;
+        let signature = self.identity.sign(&challenge_message(&challenge, &record.hash));
+        let response = ureq::get(&format!("{}{}{}", url, BOOK_PATH, record.hash))
+            .set(DID_HEADER, &self.identity.did())
+            .set(CHALLENGE_HEADER, &challenge)
+            .set(SIGNATURE_HEADER, &hex_encode(signature))
+            .timeout(FETCH_TIMEOUT)
+            .call()
+            .map_err(Box::new)?;
+        //Never read more data than the hash paid for
+        let limit = record.price / PRICE_PER_BYTE;
+        let mut body = Vec::new();
+        response.into_reader().take(limit + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > limit {
+            return Err(Error::PeerProtocol(format!("Book is larger than the {} bytes paid for", limit)));
+        }
+        let book = Book::from_json(&body)?;
+        if !book.commitments()?.iter().any(|commitment| hex_encode(commitment) == record.hash) {
+            return Err(Error::InvalidBook(format!("Book does not match Hash({})", record.hash)));
+        }
+        Ok(book)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Page, test_util};
+    use crate::book::HashAlgorithm;
+    use crate::database::{HashStatus, RootDIDRecord};
+    use crate::did::dht_public_key;
+    use crate::storage::Record;
+
+    //Serves the books of the storage on a local port, returns its url.
+    fn start(storage: &StorageHandle) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let storage = storage.clone();
+        std::thread::spawn(move|| serve_requests(server, &test_util::config(), &storage));
+        url
+    }
+
+    //Indexes the DID as a Root Node published on chain.
+    fn publish_did(storage: &StorageHandle, did: &str) {
+        let public_key = dht_public_key(did).map(|public_key| hex_encode(public_key.as_bytes()));
+        let record = RootDIDRecord{did: hex_encode(did), block_height: 1, price: 1000, public_key, location: None};
+        storage.open().unwrap().add_record(&Record::RootDID(record)).unwrap();
+    }
+
+    //Shares are recorded once the book was sent, after the peer has it.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let started = Instant::now();
+        while !condition() {
+            if started.elapsed() > Duration::from_secs(5) {return false;}
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    fn status(error: Error) -> Option<u16> {
+        http_status(&error)
+    }
+
+    #[test]
+    fn serve_once_per_peer() {
+        let server_storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        server_storage.books().unwrap().insert(&hash, &book).unwrap();
+        let url = start(&server_storage);
+
+        let client_storage = StorageHandle::memory().unwrap();
+        let fetcher = Fetcher::new(&test_util::config(), &client_storage).unwrap();
+        let record = HashRecord{hash: hash.clone(), block_height: 1, price: 1000, status: HashStatus::Pending,
+            algorithm: HashAlgorithm::Sha1, location: None};
+        //Only Root Nodes published on chain are served
+        assert_eq!(fetcher.fetch(&url, &record).map_err(status).err(), Some(Some(401)));
+        publish_did(&server_storage, &client_storage.identity().unwrap().did());
+        assert_eq!(fetcher.fetch(&url, &record).unwrap(), book);
+        assert_eq!(fetcher.fetch(&url, &record).map_err(status).err(), Some(Some(403)));
+        let did = hex_encode(client_storage.identity().unwrap().did());
+        assert!(eventually(|| server_storage.open().unwrap().share_stats().unwrap() == vec![(did.clone(), 1)]));
+
+        let missing = HashRecord{hash: "00".repeat(20), ..record};
+        assert_eq!(fetcher.fetch(&url, &missing).map_err(status).err(), Some(Some(404)));
+        let post = ureq::post(&format!("{}{}", url, CHALLENGE_PATH)).call();
+        assert!(matches!(post, Err(ureq::Error::Status(405, _))));
+    }
+
+    //Two requests for the same book at once can not both be answered.
+    #[test]
+    fn concurrent_requests() {
+        let server_storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        server_storage.books().unwrap().insert(&hash, &book).unwrap();
+        let url = start(&server_storage);
+        let client_storage = StorageHandle::memory().unwrap();
+        publish_did(&server_storage, &client_storage.identity().unwrap().did());
+        let record = HashRecord{hash, block_height: 1, price: 1000, status: HashStatus::Pending,
+            algorithm: HashAlgorithm::Sha1, location: None};
+        let fetches: Vec<_> = (0..8).map(|_| {
+            let (url, record, client_storage) = (url.clone(), record.clone(), client_storage.clone());
+            std::thread::spawn(move|| Fetcher::new(&test_util::config(), &client_storage).unwrap().fetch(&url, &record).is_ok())
+        }).collect();
+        let served = fetches.into_iter().map(|fetch| fetch.join().unwrap()).filter(|served| *served).count();
+        assert_eq!(served, 1);
+    }
+
+    struct Node {
+        storage: StorageHandle,
+        did: String,
+        url: String,
+    }
+
+    //Root Nodes serving on localhost, each published on chain in every index
+    //and reachable through addpeer.
+    fn nodes(count: usize) -> (Vec<Node>, Config) {
+        let nodes: Vec<Node> = (0..count).map(|_| {
+            let storage = StorageHandle::memory().unwrap();
+            let did = storage.identity().unwrap().did();
+            let url = start(&storage);
+            Node{storage, did, url}
+        }).collect();
+        for node in &nodes {
+            for other in &nodes {
+                publish_did(&node.storage, &other.did);
+            }
+        }
+        let peers = nodes.iter().map(|node| (node.did.clone(), node.url.clone())).collect();
+        (nodes, Config{peers, ..test_util::config()})
+    }
+
+    fn pending(storage: &StorageHandle, book: &Book) -> String {
+        let hash = hex_encode(book.hash());
+        let record = HashRecord{hash: hash.clone(), block_height: 1, price: 1000, status: HashStatus::Pending,
+            algorithm: HashAlgorithm::Sha1, location: None};
+        storage.open().unwrap().add_record(&Record::Hash(record)).unwrap();
+        hash
+    }
+
+    fn status_of(storage: &StorageHandle, hash: &str) -> HashStatus {
+        storage.open().unwrap().hash(hash).unwrap().unwrap().status
+    }
+
+    //A book published on one node reaches the others, also through a node
+    //that only just fetched it.
+    #[test]
+    fn books_spread_between_nodes() {
+        let (nodes, config) = nodes(3);
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        nodes[0].storage.books().unwrap().insert(&hash, &book).unwrap();
+        pending(&nodes[1].storage, &book);
+        Fetcher::new(&config, &nodes[1].storage).unwrap().fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[1].storage, &hash), HashStatus::Valid);
+        assert_eq!(nodes[1].storage.books().unwrap().get(&hash).unwrap(), Some(book.clone()));
+
+        //The first node only shares the book once, the second still can
+        nodes[0].storage.open().unwrap().add_share(&hash, &hex_encode(&nodes[2].did)).unwrap();
+        pending(&nodes[2].storage, &book);
+        Fetcher::new(&config, &nodes[2].storage).unwrap().fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[2].storage, &hash), HashStatus::Valid);
+        assert!(eventually(|| nodes[1].storage.open().unwrap().share_stats().unwrap() == vec![(hex_encode(&nodes[2].did), 1)]));
+    }
+
+    //Books published by their SHA-256 are fetched and checked like any other.
+    #[test]
+    fn sha256_books() {
+        let (nodes, config) = nodes(2);
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.sha256());
+        nodes[0].storage.books().unwrap().insert(&hash, &book).unwrap();
+        let record = HashRecord{hash: hash.clone(), block_height: 1, price: 1000, status: HashStatus::Pending,
+            algorithm: HashAlgorithm::Sha256, location: None};
+        nodes[1].storage.open().unwrap().add_record(&Record::Hash(record)).unwrap();
+        Fetcher::new(&config, &nodes[1].storage).unwrap().fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[1].storage, &hash), HashStatus::Valid);
+        assert_eq!(nodes[1].storage.books().unwrap().get(&hash).unwrap(), Some(book));
+    }
+
+    //Peers that do not have the book are backed off from, peers that already
+    //shared it with us are not asked again.
+    #[test]
+    fn retries() {
+        let (nodes, config) = nodes(3);
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        nodes[1].storage.books().unwrap().insert(&hash, &book).unwrap();
+        nodes[1].storage.open().unwrap().add_share(&hash, &hex_encode(&nodes[0].did)).unwrap();
+        pending(&nodes[0].storage, &book);
+        let mut fetcher = Fetcher::new(&config, &nodes[0].storage).unwrap();
+        fetcher.fetch_pending().unwrap();
+        let refused = &fetcher.retries[&(hash.clone(), nodes[1].did.clone())];
+        assert_eq!((refused.failures, refused.at), (1, None));
+        let missing = &fetcher.retries[&(hash.clone(), nodes[2].did.clone())];
+        assert!(missing.at.is_some() && !missing.due());
+
+        //Not asked again until the retry is due
+        nodes[2].storage.books().unwrap().insert(&hash, &book).unwrap();
+        fetcher.fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[0].storage, &hash), HashStatus::Pending);
+        fetcher.retries.get_mut(&(hash.clone(), nodes[2].did.clone())).unwrap().at = Some(Instant::now());
+        fetcher.fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[0].storage, &hash), HashStatus::Valid);
+        //Retries are forgotten once the hash is no longer pending
+        fetcher.fetch_pending().unwrap();
+        assert!(fetcher.retries.is_empty());
+    }
+
+    //A peer sending more than the hash paid for is backed off from like any
+    //failing peer, the hash stays pending and the next peer is asked.
+    #[test]
+    fn oversized_book() {
+        let (nodes, mut config) = nodes(2);
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        //Padding sent by a peer asked before the honest one
+        let padder = (1..).map(test_util::did).find(|did| *did < nodes[1].did).unwrap();
+        let (url, _) = test_util::http_server(HashMap::from([
+            (format!("GET {}", CHALLENGE_PATH), (200, b"challenge".to_vec())),
+            (format!("GET {}{}", BOOK_PATH, hash), (200, vec![b' '; 2000])),
+        ]));
+        publish_did(&nodes[0].storage, &padder);
+        config.peers.push((padder.clone(), url));
+        pending(&nodes[0].storage, &book);
+
+        let mut fetcher = Fetcher::new(&config, &nodes[0].storage).unwrap();
+        fetcher.fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[0].storage, &hash), HashStatus::Pending);
+        let padded = &fetcher.retries[&(hash.clone(), padder)];
+        assert!(padded.at.is_some() && !padded.due());
+        assert!(fetcher.retries.contains_key(&(hash.clone(), nodes[1].did.clone())));
+
+        nodes[1].storage.books().unwrap().insert(&hash, &book).unwrap();
+        fetcher.retries.get_mut(&(hash.clone(), nodes[1].did.clone())).unwrap().at = Some(Instant::now());
+        fetcher.fetch_pending().unwrap();
+        assert_eq!(status_of(&nodes[0].storage, &hash), HashStatus::Valid);
+    }
+
+    //Only a signature made with the key of the requesting DID over the
+    //challenge handed out and the hash asked for is accepted.
+    #[test]
+    fn authentication() {
+        let server_storage = StorageHandle::memory().unwrap();
+        let book = Book{pages: vec![Page{price: 5, data: "hello".to_string()}]};
+        let hash = hex_encode(book.hash());
+        server_storage.books().unwrap().insert(&hash, &book).unwrap();
+        let url = start(&server_storage);
+        let identity = StorageHandle::memory().unwrap().identity().unwrap();
+        let other = StorageHandle::memory().unwrap().identity().unwrap();
+        publish_did(&server_storage, &identity.did());
+        publish_did(&server_storage, &other.did());
+
+        let challenge = || ureq::get(&format!("{}{}", url, CHALLENGE_PATH)).call().unwrap().into_string().unwrap();
+        let request = |challenge: &str, signature: &str| {
+            match ureq::get(&format!("{}{}{}", url, BOOK_PATH, hash))
+                .set(DID_HEADER, &identity.did())
+                .set(CHALLENGE_HEADER, challenge)
+                .set(SIGNATURE_HEADER, signature)
+                .call() {
+                Ok(response) => response.status(),
+                Err(ureq::Error::Status(status, _)) => status,
+                Err(e) => panic!("{}", e)
+            }
+        };
+        let sign = |identity: &Identity, challenge: &str, hash: &str| hex_encode(identity.sign(&challenge_message(challenge, hash)));
+
+        let issued = challenge();
+        assert_eq!(request(&issued, &sign(&identity, &issued, &"00".repeat(20))), 401);
+        let issued = challenge();
+        assert_eq!(request(&issued, &sign(&identity, &challenge(), &hash)), 401);
+        let issued = challenge();
+        assert_eq!(request(&issued, &sign(&other, &issued, &hash)), 401);
+        let issued = challenge();
+        assert_eq!(request(&issued, "not hex"), 401);
+        let issued = challenge();
+        assert_eq!(request(&issued, &sign(&identity, &issued, &hash)[2..]), 401);
+        //Challenges are never handed out by the peer itself
+        assert_eq!(request(&"00".repeat(CHALLENGE_SIZE), &sign(&identity, &"00".repeat(CHALLENGE_SIZE), &hash)), 401);
+        let issued = challenge();
+        assert_eq!(request(&issued, &sign(&identity, &issued, &hash)), 200);
+        //A challenge can only be used once
+        assert_eq!(request(&issued, &sign(&identity, &issued, &hash)), 401);
+    }
+}
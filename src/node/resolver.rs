@@ -0,0 +1,347 @@
+use crate::{Error, Config, Value};
+use crate::{hex_encode, hex_decode};
+use crate::database::DIDDocument;
+use crate::storage::{Storage, StorageHandle};
+use crate::did::{self, dht_public_key, DID_DHT_PREFIX};
+use crate::did_method::{self, multibase_public_key, WebMethod, DID_WEB_PREFIX};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ed25519_dalek::VerifyingKey;
+use simple_dns::Packet;
+use simple_dns::rdata::RData;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//did:dht documents are DNS packets published as BEP44 mutable items signed
+//by the key of the DID. Gateways and Pkarr relays serve them at
+//GET <gateway>/<z-base-32 suffix> as the 64 byte signature, the 8 byte big
+//endian sequence number and the DNS packet.
+//https://did-dht.com/#gateway-api
+const SIGNATURE_SIZE: usize = 64;
+const SEQUENCE_SIZE: usize = 8;
+const MAXIMUM_PACKET_SIZE: usize = 1000;
+const MAXIMUM_DOCUMENT_SIZE: u64 = 64 * 1024;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(30);
+const CACHE_EXPIRY: u64 = 60 * 60;
+//DIDs that could not be resolved are only tried again after this long
+const FAILURE_EXPIRY: u64 = 5 * 60;
+//Type of the service in a Root Node's DID document that books are served on
+pub const SERVICE_TYPE: &str = "TBPUBRootNode";
+
+fn now() -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+//The BEP44 signature covers the bencoded sequence number and value.
+fn signed_message(sequence: u64, packet: &[u8]) -> Vec<u8> {
+    let mut message = format!("3:seqi{}e1:v{}:", sequence, packet.len()).into_bytes();
+    message.extend_from_slice(packet);
+    message
+}
+
+//Service records are TXT records named _sN._did holding id=..;t=..;se=..
+fn service_endpoint(packet: &[u8]) -> Result<Option<String>, Error> {
+    let packet = Packet::parse(packet).map_err(|e| Error::DIDResolution(e.to_string()))?;
+    for answer in packet.answers {
+        if !answer.name.to_string().starts_with("_s") {continue;}
+        let txt = match answer.rdata {
+            RData::TXT(txt) => String::try_from(txt).unwrap_or_default(),
+            _ => continue
+        };
+        let properties: Vec<(&str, &str)> = txt.split(';')
+            .filter_map(|property| property.split_once('='))
+            .collect();
+        if !properties.contains(&("t", SERVICE_TYPE)) {continue;}
+        if let Some((_, endpoint)) = properties.iter().find(|(key, _)| *key == "se") {
+            return Ok(Some(endpoint.split(',').next().unwrap_or_default().to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn service_type_matches(service: &Value) -> bool {
+    match &service["type"] {
+        Value::String(service_type) => service_type == SERVICE_TYPE,
+        Value::Array(service_types) => service_types.iter().any(|service_type| service_type == SERVICE_TYPE),
+        _ => false
+    }
+}
+
+//did:web documents are JSON with the key as a verification method, either as
+//a multibase Ed25519 key or a JWK, and the endpoint as a service.
+fn web_document(did: &str, document: &Value) -> Result<(Option<VerifyingKey>, Option<String>), Error> {
+    if document["id"] != did {
+        return Err(Error::DIDResolution(format!("Document is for {} not {}", document["id"], did)));
+    }
+    let methods = document["verificationMethod"].as_array().cloned().unwrap_or_default();
+    let public_key = methods.iter().find_map(|method| {
+        if let Some(multibase) = method["publicKeyMultibase"].as_str() {
+            return multibase_public_key(multibase);
+        }
+        let jwk = &method["publicKeyJwk"];
+        if jwk["kty"] != "OKP" || jwk["crv"] != "Ed25519" {return None;}
+        let key = URL_SAFE_NO_PAD.decode(jwk["x"].as_str()?).ok()?;
+        VerifyingKey::from_bytes(&key.try_into().ok()?).ok()
+    });
+    let services = document["service"].as_array().cloned().unwrap_or_default();
+    let endpoint = services.iter()
+        .filter(|service| service_type_matches(service))
+        .find_map(|service| match &service["serviceEndpoint"] {
+            Value::String(endpoint) => Some(endpoint.clone()),
+            Value::Array(endpoints) => endpoints.iter().find_map(|endpoint| endpoint.as_str().map(String::from)),
+            _ => None
+        });
+    Ok((public_key, endpoint))
+}
+
+pub struct Resolver {
+    gateway: String,
+    storage: Box<dyn Storage>,
+}
+
+impl Resolver {
+    pub fn new(config: &Config, storage: &StorageHandle) -> Result<Resolver, Error> {
+        Ok(Resolver{
+            gateway: config.didgateway.trim_end_matches('/').to_string(),
+            storage: storage.open()?,
+        })
+    }
+
+    //The url the Root Node with this DID serves books on.
+    pub fn resolve_endpoint(&self, did: &str) -> Result<Option<String>, Error> {
+        Ok(self.resolve(did)?.and_then(|document| document.endpoint))
+    }
+
+    //The key of the DID, from the DID itself if its method embeds it and
+    //otherwise from its DID document.
+    pub fn public_key(&self, did: &str) -> Result<Option<Vec<u8>>, Error> {
+        let method = match did_method::method(did) {
+            Some(method) => method,
+            None => return Ok(None)
+        };
+        if let Some(public_key) = method.public_key(did) {return Ok(Some(public_key.as_bytes().to_vec()));}
+        match self.resolve(did)?.and_then(|document| document.public_key) {
+            Some(public_key) => Ok(Some(hex_decode(public_key)?)),
+            None => Ok(None)
+        }
+    }
+
+    //The DID document from the cache if it is recent and otherwise from the
+    //gateway or web server. A stale cache entry is still used if the document
+    //can not be fetched. did:key has no document.
+    fn resolve(&self, did: &str) -> Result<Option<DIDDocument>, Error> {
+        if did_method::method(did).is_none() {return Ok(None);}
+        let cached = self.storage.did_document(did)?;
+        if let Some(document) = &cached {
+            if now()? < document.fetched + CACHE_EXPIRY {return Ok(cached);}
+        }
+        let fetched = if did.starts_with(DID_DHT_PREFIX) {
+            self.fetch(did, cached.as_ref().map(|document| document.sequence))
+        } else if did.starts_with(DID_WEB_PREFIX) {
+            self.fetch_web(did).map(Some)
+        } else {
+            return Ok(None);
+        };
+        match fetched {
+            Ok(Some(document)) => {
+                self.storage.set_did_document(did, &document)?;
+                Ok(Some(document))
+            },
+            Ok(None) => {
+                let cached = cached.map(|document| DIDDocument{fetched: now().unwrap_or_default(), ..document});
+                if let Some(document) = &cached {self.storage.set_did_document(did, document)?;}
+                Ok(cached)
+            },
+            Err(e) => {
+                println!("[ERROR] Could not resolve {}: {}", did, e);
+                //The failure is cached as well, dated so it expires after FAILURE_EXPIRY
+                let failed = cached.clone().unwrap_or(DIDDocument{endpoint: None, public_key: None, sequence: 0, fetched: 0});
+                let fetched = (now()? + FAILURE_EXPIRY).saturating_sub(CACHE_EXPIRY);
+                self.storage.set_did_document(did, &DIDDocument{fetched, ..failed})?;
+                Ok(cached)
+            }
+        }
+    }
+
+    //did:web documents are served over https by the domain of the DID.
+    fn fetch_web(&self, did: &str) -> Result<DIDDocument, Error> {
+        let url = WebMethod.document_url(did).ok_or(Error::DIDResolution(format!("{} is not a did:web identifier", did)))?;
+        let response = ureq::get(&url)
+            .timeout(RESOLVE_TIMEOUT)
+            .call()
+            .map_err(Box::new)?;
+        let mut body = Vec::new();
+        response.into_reader().take(MAXIMUM_DOCUMENT_SIZE).read_to_end(&mut body)?;
+        let (public_key, endpoint) = web_document(did, &serde_json::from_slice(&body)?)?;
+        Ok(DIDDocument{
+            endpoint,
+            public_key: public_key.map(|public_key| hex_encode(public_key.as_bytes())),
+            sequence: 0,
+            fetched: now()?,
+        })
+    }
+
+    //Returns None if the gateway only has a document older than the one cached.
+    fn fetch(&self, did: &str, cached_sequence: Option<u64>) -> Result<Option<DIDDocument>, Error> {
+        let public_key = dht_public_key(did).ok_or(Error::DIDResolution(format!("{} is not a did:dht identifier", did)))?;
+        //The suffix is z-base-32 so it is safe to put in the url
+        let suffix = &did[DID_DHT_PREFIX.len()..];
+        let response = ureq::get(&format!("{}/{}", self.gateway, suffix))
+            .timeout(RESOLVE_TIMEOUT)
+            .call()
+            .map_err(Box::new)?;
+        let mut body = Vec::new();
+        response.into_reader()
+            .take((SIGNATURE_SIZE + SEQUENCE_SIZE + MAXIMUM_PACKET_SIZE + 1) as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < SIGNATURE_SIZE + SEQUENCE_SIZE || body.len() > SIGNATURE_SIZE + SEQUENCE_SIZE + MAXIMUM_PACKET_SIZE {
+            return Err(Error::DIDResolution(format!("Malformed record of {} bytes", body.len())));
+        }
+        let (signature, rest) = body.split_at(SIGNATURE_SIZE);
+        let (sequence, packet) = rest.split_at(SEQUENCE_SIZE);
+        let sequence = u64::from_be_bytes(sequence.try_into()?);
+        if !did::verify(public_key.as_bytes(), &signed_message(sequence, packet), signature) {
+            return Err(Error::DIDResolution("Record is not signed by the DID".to_string()));
+        }
+        if cached_sequence.is_some_and(|cached_sequence| cached_sequence > sequence) {return Ok(None);}
+        Ok(Some(DIDDocument{
+            endpoint: service_endpoint(packet)?,
+            public_key: None,
+            sequence,
+            fetched: now()?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{json, test_util};
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::collections::HashMap;
+
+    //A DNS reply with one TXT record per (name, text), in wire format.
+    fn packet(records: &[(&str, &str)]) -> Vec<u8> {
+        let mut packet = vec![0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, records.len() as u8, 0x00, 0x00, 0x00, 0x00];
+        for (name, text) in records {
+            for label in name.split('.').filter(|label| !label.is_empty()) {
+                packet.push(label.len() as u8);
+                packet.extend_from_slice(label.as_bytes());
+            }
+            packet.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x1c, 0x20]);
+            packet.extend_from_slice(&(text.len() as u16 + 1).to_be_bytes());
+            packet.push(text.len() as u8);
+            packet.extend_from_slice(text.as_bytes());
+        }
+        packet
+    }
+
+    //The record a relay serves for the DID of the seed, as test_util::did.
+    fn relay_record(seed: u8, sequence: u64, packet: &[u8]) -> Vec<u8> {
+        let signature = SigningKey::from_bytes(&[seed; 32]).sign(&signed_message(sequence, packet));
+        [&signature.to_bytes()[..], &sequence.to_be_bytes(), packet].concat()
+    }
+
+    //The signature buffer of the first BEP44 test vector.
+    #[test]
+    fn bep44_signed_message() {
+        assert_eq!(signed_message(1, b"Hello World!"), b"3:seqi1e1:v12:Hello World!");
+        assert_eq!(signed_message(0, b""), b"3:seqi0e1:v0:");
+        assert_eq!(signed_message(1234567890, &[0xff; 3]), [&b"3:seqi1234567890e1:v3:"[..], &[0xff; 3]].concat());
+    }
+
+    #[test]
+    fn service_endpoints() {
+        let service = "id=tbpub;t=TBPUBRootNode;se=http://node.example:9000,http://backup.example:9000";
+        assert_eq!(service_endpoint(&packet(&[("_s0._did.", service)])).unwrap(), Some("http://node.example:9000".to_string()));
+        //Keys, other services and records that are not services are skipped
+        let records = [
+            ("_k0._did.", "id=0;t=0;k=YCcHYL2sYNPDlKaALcEmll2HHyT968M4UWbr-9CFGWE"),
+            ("_s0._did.", "id=dwn;t=DecentralizedWebNode;se=https://dwn.example"),
+            ("_did.", "v=0;vm=k0;svc=s0,s1"),
+            ("_s1._did.", "id=tbpub;t=TBPUBRootNode;se=node.example:9000"),
+        ];
+        assert_eq!(service_endpoint(&packet(&records)).unwrap(), Some("node.example:9000".to_string()));
+        assert_eq!(service_endpoint(&packet(&records[..3])).unwrap(), None);
+        assert_eq!(service_endpoint(&packet(&[])).unwrap(), None);
+        assert!(service_endpoint(&[0x00, 0x01, 0x02]).is_err());
+    }
+
+    #[test]
+    fn web_documents() {
+        let did = "did:web:node.example";
+        //The RFC 8032 test 1 key, as a did:key multibase and as a JWK
+        let key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+        let document = json!({
+            "id": did,
+            "verificationMethod": [{"id": "#key-0", "type": "Multikey", "publicKeyMultibase": "z6MktwupdmLXVVqTzCw4i46r4uGyosGXRnR3XjN4Zq7oMMsw"}],
+            "service": [
+                {"id": "#dwn", "type": "DecentralizedWebNode", "serviceEndpoint": "https://dwn.example"},
+                {"id": "#tbpub", "type": ["TBPUBRootNode"], "serviceEndpoint": ["https://node.example:9444", "https://backup.example"]}
+            ]
+        });
+        let (public_key, endpoint) = web_document(did, &document).unwrap();
+        assert_eq!(hex_encode(public_key.unwrap().as_bytes()), key);
+        assert_eq!(endpoint, Some("https://node.example:9444".to_string()));
+
+        let jwk = json!({"id": did, "verificationMethod": [
+            {"publicKeyJwk": {"kty": "EC", "crv": "secp256k1", "x": "AAAA"}},
+            {"publicKeyJwk": {"kty": "OKP", "crv": "Ed25519", "x": URL_SAFE_NO_PAD.encode(hex_decode(key).unwrap())}}
+        ]});
+        let (public_key, endpoint) = web_document(did, &jwk).unwrap();
+        assert_eq!(hex_encode(public_key.unwrap().as_bytes()), key);
+        assert_eq!(endpoint, None);
+
+        //A document served for another DID is never trusted
+        assert!(web_document("did:web:other.example", &document).is_err());
+        assert_eq!(web_document(did, &json!({"id": did})).unwrap(), (None, None));
+    }
+
+    #[test]
+    fn fetch_from_relay() {
+        let service = packet(&[("_s0._did.", "id=tbpub;t=TBPUBRootNode;se=http://node.example:9000")]);
+        let did = test_util::did(1);
+        let forged = test_util::did(2);
+        let mut tampered = relay_record(2, 1, &service);
+        *tampered.last_mut().unwrap() ^= 1;
+        let routes = HashMap::from([
+            (format!("GET /{}", &did[DID_DHT_PREFIX.len()..]), (200, relay_record(1, 7, &service))),
+            (format!("GET /{}", &forged[DID_DHT_PREFIX.len()..]), (200, tampered)),
+        ]);
+        let (url, _) = test_util::http_server(routes);
+        let config = Config{didgateway: format!("{}/", url), ..test_util::config()};
+        let storage = StorageHandle::memory().unwrap();
+        let resolver = Resolver::new(&config, &storage).unwrap();
+
+        assert_eq!(resolver.resolve_endpoint(&did).unwrap(), Some("http://node.example:9000".to_string()));
+        let document = storage.open().unwrap().did_document(&did).unwrap().unwrap();
+        assert_eq!(document.sequence, 7);
+        //The key of a did:dht is in the DID itself
+        assert_eq!(resolver.public_key(&did).unwrap(), Some(dht_public_key(&did).unwrap().as_bytes().to_vec()));
+        //Records not signed by the DID are rejected
+        assert_eq!(resolver.resolve_endpoint(&forged).unwrap(), None);
+
+        //A relay serving an older record than the one cached does not replace it
+        let newer = DIDDocument{endpoint: Some("http://newer.example".to_string()), public_key: None, sequence: 8, fetched: 0};
+        storage.open().unwrap().set_did_document(&did, &newer).unwrap();
+        assert_eq!(resolver.resolve_endpoint(&did).unwrap(), Some("http://newer.example".to_string()));
+        assert_eq!(storage.open().unwrap().did_document(&did).unwrap().unwrap().sequence, 8);
+    }
+
+    //A gateway that can not resolve a DID is not asked again on every lookup.
+    #[test]
+    fn failures_are_cached() {
+        let (url, requests) = test_util::http_server(HashMap::new());
+        let config = Config{didgateway: url, ..test_util::config()};
+        let storage = StorageHandle::memory().unwrap();
+        let resolver = Resolver::new(&config, &storage).unwrap();
+        let stale = DIDDocument{endpoint: Some("http://stale".to_string()), public_key: None, sequence: 1, fetched: 0};
+        storage.open().unwrap().set_did_document(&test_util::did(2), &stale).unwrap();
+        for _ in 0..3 {
+            assert_eq!(resolver.resolve_endpoint(&test_util::did(1)).unwrap(), None);
+            assert_eq!(resolver.resolve_endpoint(&test_util::did(2)).unwrap(), Some("http://stale".to_string()));
+        }
+        assert_eq!(requests.try_iter().count(), 2);
+    }
+}
@@ -0,0 +1,729 @@
+use crate::{Error, Config, Value, json};
+use crate::chain_source::{self, ChainSource};
+use crate::{Mempool, BookStore};
+use crate::database::{HashRecord, HashStatus, RootDIDRecord, LoserRecord, TxLocation};
+use crate::storage::{Storage, StorageHandle, Record, IndexedBlock};
+use crate::tbpub_transaction::{select_winner, Candidate};
+use crate::MINIMUM_BLOCK_HEIGHT;
+
+use bitcoin::{Block, BlockHash};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+//Blocks every fetch worker may be ahead of the next block to commit
+const FETCH_AHEAD: usize = 4;
+const WAIT_FOR_BLOCK_TIMEOUT: u64 = 10000;
+const MINIMUM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAXIMUM_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const BLOCK_NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(60);
+const SCAN_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct ProgressState {
+    //Height and time the initial block scan started at
+    start: Option<(u64, Instant)>,
+    block_height: u64,
+    top_block: u64,
+}
+
+//Progress of the initial block scan, shared with the cli for getinfo.
+#[derive(Clone, Default)]
+pub struct ScanProgress {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl ScanProgress {
+    //Scanning again after a reorg or a new tip keeps the original start.
+    fn start(&self, block_height: u64, top_block: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.start.is_none() {state.start = Some((block_height, Instant::now()));}
+        state.block_height = block_height;
+        state.top_block = top_block;
+    }
+
+    fn update(&self, block_height: u64) {
+        self.state.lock().unwrap().block_height = block_height;
+    }
+
+    //A rollback below where the scan started restarts the rate from there.
+    fn rollback(&self, block_height: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.block_height = block_height;
+        if let Some((start_height, _)) = state.start {
+            if block_height < start_height {state.start = Some((block_height, Instant::now()));}
+        }
+    }
+
+    pub fn finish(&self) {
+        self.state.lock().unwrap().start = None;
+    }
+
+    //Null once the initial block scan is finished. The rate is averaged over
+    //the whole scan so far, the ETA is in seconds.
+    pub fn to_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let (start_height, started) = match state.start {
+            Some(start) => start,
+            None => return json!(null)
+        };
+        let remaining = (state.top_block + 1).saturating_sub(state.block_height);
+        let total = (state.top_block + 1).saturating_sub(MINIMUM_BLOCK_HEIGHT).max(1);
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => state.block_height.saturating_sub(start_height) as f64 / elapsed,
+            false => 0.0
+        };
+        let mut result: Value = json!(null);
+        result["block_height"] = json!(state.block_height);
+        result["top_block"] = json!(state.top_block);
+        result["blocks_remaining"] = json!(remaining);
+        result["progress"] = json!(state.block_height.saturating_sub(MINIMUM_BLOCK_HEIGHT) as f64 / total as f64);
+        result["blocks_per_second"] = json!(rate);
+        result["eta"] = match rate > 0.0 {
+            true => json!((remaining as f64 / rate).round() as u64),
+            false => json!(null)
+        };
+        result
+    }
+}
+
+//A block fetched and indexed, waiting to be committed.
+struct FetchedBlock {
+    block: Block,
+    indexed: IndexedBlock,
+}
+
+pub struct Scanner {
+    pub block_height: u64,
+    //Tip of the best chain when it was last looked at
+    pub top_block: u64,
+    //Cleared once the scan first reached the tip, until then blocks are
+    //fetched by scan_pipelined
+    pub initial_block_scan: bool,
+    //How long to wait between polls of a chain source that can not wait for
+    //blocks, doubled every time no block arrived
+    poll_interval: Duration,
+    config: Config,
+    storage: Box<dyn Storage>,
+    mempool: Mempool,
+    books: BookStore,
+    progress: ScanProgress,
+    //Set by a rollback, books are only collected once the rescan caught up
+    //so the ones whose transaction is mined again on the best chain stay
+    garbage: bool,
+}
+
+impl Scanner {
+    pub fn new(config: &Config, storage: &StorageHandle, block_height: u64, mempool: Mempool, progress: ScanProgress) -> Result<Scanner, Error> {
+        let books = storage.books()?;
+        let storage = storage.open()?;
+        let initial_block_scan = storage.setting("initial_block_scan")?.as_deref() != Some("0");
+        Ok(Scanner{
+            block_height,
+            top_block: 0,
+            initial_block_scan,
+            poll_interval: MINIMUM_POLL_INTERVAL,
+            config: config.clone(),
+            storage,
+            mempool,
+            books,
+            progress,
+            garbage: false,
+        })
+    }
+
+    //Follows the tip of the best chain for as long as the node runs. Errors
+    //are logged and the scan is tried again from the last committed block.
+    pub fn follow(&mut self, chain: &dyn ChainSource, block_notifications: Option<Receiver<()>>) -> ! {
+        loop {
+            if let Err(error) = self.sync(chain).and_then(|_| self.wait_for_block(chain, block_notifications.as_ref())) {
+                println!("[ERROR] Scan stopped at block {}: {}", self.block_height, error);
+                std::thread::sleep(SCAN_RETRY_INTERVAL);
+            }
+        }
+    }
+
+    //Scans up to the tip, returns once every block is indexed and the last
+    //one is still on the best chain. The initial block scan is pipelined up
+    //to the tip and then checks for blocks found in the meantime.
+    pub fn sync(&mut self, chain: &dyn ChainSource) -> Result<(), Error> {
+        self.update_top_block(chain)?;
+        loop {
+            while self.block_height <= self.top_block {
+                match self.initial_block_scan {
+                    true => {
+                        self.scan_pipelined(chain, self.top_block)?;
+                        self.update_top_block(chain)?;
+                    },
+                    false => {self.scan_next(chain)?;}
+                }
+            }
+            if self.initial_block_scan {
+                self.storage.set_setting("initial_block_scan", "0")?;
+                self.initial_block_scan = false;
+                self.progress.finish();
+                println!("[INFO] Initial Block Scan finished at block {}", self.top_block);
+            }
+            self.collect_garbage()?;
+            if !self.check_tip(chain)? {return Ok(());}
+        }
+    }
+
+    //Waits for the next block on the block notifications if there are any,
+    //else on the chain source, falling back to polling with backoff if it
+    //can not wait.
+    pub fn wait_for_block(&mut self, chain: &dyn ChainSource, block_notifications: Option<&Receiver<()>>) -> Result<(), Error> {
+        match block_notifications {
+            Some(receiver) => {let _ = receiver.recv_timeout(BLOCK_NOTIFICATION_TIMEOUT);},
+            None => if chain.wait_for_block(WAIT_FOR_BLOCK_TIMEOUT).is_err() {
+                std::thread::sleep(self.poll_interval);
+                self.poll_interval = std::cmp::min(self.poll_interval * 2, MAXIMUM_POLL_INTERVAL);
+            }
+        }
+        self.update_top_block(chain)
+    }
+
+    fn update_top_block(&mut self, chain: &dyn ChainSource) -> Result<(), Error> {
+        let top_block = chain.block_count()?;
+        if top_block != self.top_block {
+            println!("[INFO] Top Block: {}", top_block);
+            self.poll_interval = MINIMUM_POLL_INTERVAL;
+        }
+        self.top_block = top_block;
+        Ok(())
+    }
+
+    //Returns the hash we indexed at the given height, if any.
+    fn stored_hash(&self, block_height: u64) -> Result<Option<BlockHash>, Error> {
+        match self.storage.block_hash(block_height)? {
+            Some(hash) => Ok(Some(BlockHash::from_str(&hash)?)),
+            None => Ok(None)
+        }
+    }
+
+    //Walks back from the last scanned block until the stored hash matches the
+    //best chain again. Heights without a stored hash are treated as matching,
+    //they were scanned before block hashes were recorded.
+    fn find_fork_point(&self, chain: &dyn ChainSource) -> Result<u64, Error> {
+        let mut height = std::cmp::min(self.block_height - 1, chain.block_count()?);
+        while height >= MINIMUM_BLOCK_HEIGHT {
+            match self.stored_hash(height)? {
+                Some(stored) if stored != chain.block_hash(height)? => {},
+                _ => return Ok(height)
+            }
+            height -= 1;
+        }
+        Ok(MINIMUM_BLOCK_HEIGHT - 1)
+    }
+
+    //Removes everything indexed above the fork point so it can be rescanned
+    //from the best chain.
+    fn rollback(&mut self, fork_point: u64) -> Result<(), Error> {
+        println!("[INFO] Rolling back to block {}", fork_point);
+        self.storage.rollback(fork_point)?;
+        self.block_height = fork_point + 1;
+        self.progress.rollback(self.block_height);
+        self.garbage = true;
+        Ok(())
+    }
+
+    //Removes the books a rollback left without an indexed hash, to be called
+    //once the scan reached the tip again.
+    pub fn collect_garbage(&mut self) -> Result<(), Error> {
+        if !self.garbage {return Ok(());}
+        let removed = self.books.collect_garbage(self.storage.as_ref())?;
+        println!("[INFO] Removed {} books no longer indexed", removed);
+        self.garbage = false;
+        Ok(())
+    }
+
+    //Returns true if the block at the tip of our index is no longer part of
+    //the best chain, and rolls back to the fork point if so.
+    pub fn check_reorg(&mut self, chain: &dyn ChainSource, block: &Block) -> Result<bool, Error> {
+        if self.block_height <= MINIMUM_BLOCK_HEIGHT {return Ok(false);}
+        match self.stored_hash(self.block_height - 1)? {
+            Some(stored) if stored != block.header.prev_blockhash => {
+                println!("[INFO] Reorg detected at block {}", self.block_height - 1);
+                let fork_point = self.find_fork_point(chain)?;
+                self.rollback(fork_point)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    //Checks the last scanned block is still on the best chain while waiting
+    //at the tip, returns true if the index was rolled back.
+    pub fn check_tip(&mut self, chain: &dyn ChainSource) -> Result<bool, Error> {
+        if self.block_height <= MINIMUM_BLOCK_HEIGHT {return Ok(false);}
+        let height = self.block_height - 1;
+        let stored = match self.stored_hash(height)? {
+            Some(stored) => stored,
+            None => return Ok(false)
+        };
+        if height <= chain.block_count()? && chain.block_hash(height)? == stored {return Ok(false);}
+        println!("[INFO] Reorg detected at block {}", height);
+        let fork_point = self.find_fork_point(chain)?;
+        self.rollback(fork_point)?;
+        Ok(true)
+    }
+
+    //Scans the next block, returns false if a reorg was found instead and the
+    //index was rolled back.
+    pub fn scan_next(&mut self, chain: &dyn ChainSource) -> Result<bool, Error> {
+        println!("[INFO] Checking block {}", self.block_height);
+        let fetched = fetch(chain, self.block_height)?;
+        println!("[INFO] Block {} has hash {}", self.block_height, fetched.indexed.block_hash);
+        if self.check_reorg(chain, &fetched.block)? {return Ok(false);}
+        self.mempool.remove_confirmed(&fetched.block);
+        self.storage.add_block(&fetched.indexed)?;
+        self.block_height += 1;
+        self.resolve_book(&fetched.indexed)?;
+        Ok(true)
+    }
+
+    //Scans up to the top block with scanworkers threads fetching blocks from
+    //their own connection to the chain while this one commits them in order,
+    //scanbatch blocks per database transaction. Blocks a worker failed to
+    //fetch are fetched again here, as are all once every worker stopped.
+    //Returns early if a reorg was found and the index was rolled back.
+    pub fn scan_pipelined(&mut self, chain: &dyn ChainSource, top_block: u64) -> Result<(), Error> {
+        self.progress.start(self.block_height, top_block);
+        let (job_sender, job_receiver) = channel::<u64>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, result_receiver) = channel::<(u64, Result<FetchedBlock, Error>)>();
+        for _ in 0..self.config.scanworkers {
+            let jobs = job_receiver.clone();
+            let results = result_sender.clone();
+            let config = self.config.clone();
+            //Workers stop once the scan drops either end of their channels
+            std::thread::spawn(move|| {
+                let chain = match chain_source::open(&config) {
+                    Ok(chain) => chain,
+                    Err(error) => {
+                        println!("[ERROR] Fetch worker could not open the chain: {}", error);
+                        return;
+                    }
+                };
+                loop {
+                    let block_height = match jobs.lock().unwrap().recv() {
+                        Ok(block_height) => block_height,
+                        Err(_) => return
+                    };
+                    if results.send((block_height, fetch(chain.as_ref(), block_height))).is_err() {return;}
+                }
+            });
+        }
+        drop(result_sender);
+
+        let mut next_job = self.block_height;
+        let queue_job = |next_job: &mut u64| {
+            if *next_job <= top_block {
+                let _ = job_sender.send(*next_job);
+                *next_job += 1;
+            }
+        };
+        for _ in 0..self.config.scanworkers * FETCH_AHEAD {
+            queue_job(&mut next_job);
+        }
+        self.scan_ordered(chain, top_block, |block_height| match result_receiver.recv() {
+            Ok((_, Ok(block))) => Ok(block),
+            Ok((failed, Err(error))) => {
+                println!("[ERROR] Could not fetch block {}, fetching it again: {}", failed, error);
+                fetch(chain, failed)
+            },
+            Err(_) => fetch(chain, block_height)
+        }, || queue_job(&mut next_job))
+    }
+
+    //Scans up to the top block fetching one block after the other from the
+    //chain, committing scanbatch blocks per database transaction. Returns
+    //early if a reorg was found and the index was rolled back.
+    pub fn scan_batched(&mut self, chain: &dyn ChainSource, top_block: u64) -> Result<(), Error> {
+        self.scan_ordered(chain, top_block, |block_height| fetch(chain, block_height), || ())
+    }
+
+    //Commits the blocks received, which may come in any order, in the order
+    //of their height. Receive is given the next height to commit, committed
+    //is called for every block taken from them.
+    fn scan_ordered(&mut self, chain: &dyn ChainSource, top_block: u64,
+            mut receive: impl FnMut(u64) -> Result<FetchedBlock, Error>, mut committed: impl FnMut()) -> Result<(), Error> {
+        //Blocks fetched out of order wait here for the ones before them
+        let mut fetched: BTreeMap<u64, FetchedBlock> = BTreeMap::new();
+        let mut batch: Vec<IndexedBlock> = Vec::new();
+        let mut previous = self.stored_hash(self.block_height - 1)?;
+        let mut block_height = self.block_height;
+        while block_height <= top_block {
+            let block = match fetched.remove(&block_height) {
+                Some(block) => block,
+                None => {
+                    let block = receive(block_height)?;
+                    fetched.insert(block.indexed.block_height, block);
+                    continue;
+                }
+            };
+            //The check against the committed tip rolls back to the fork point
+            if previous.is_some_and(|previous| previous != block.block.header.prev_blockhash) {
+                self.commit(&mut batch)?;
+                self.check_reorg(chain, &block.block)?;
+                return Ok(());
+            }
+            previous = Some(block.block.block_hash());
+            self.mempool.remove_confirmed(&block.block);
+            batch.push(block.indexed);
+            block_height += 1;
+            committed();
+            if batch.len() >= self.config.scanbatch {self.commit(&mut batch)?;}
+        }
+        self.commit(&mut batch)
+    }
+
+    fn commit(&mut self, batch: &mut Vec<IndexedBlock>) -> Result<(), Error> {
+        let (first, last) = match (batch.first(), batch.last()) {
+            (Some(first), Some(last)) => (first.block_height, last.block_height),
+            _ => return Ok(())
+        };
+        self.storage.add_blocks(batch)?;
+        self.block_height = last + 1;
+        self.progress.update(self.block_height);
+        println!("[INFO] Scanned blocks {} to {}", first, last);
+        for block in batch.drain(..) {
+            self.resolve_book(&block)?;
+        }
+        Ok(())
+    }
+
+    //Books kept through a reorg are resolved again right away
+    fn resolve_book(&self, block: &IndexedBlock) -> Result<(), Error> {
+        if let Some(Record::Hash(record)) = &block.winner {
+            if let Some(book) = self.books.get(&record.hash)? {
+                self.storage.resolve_hash(&record.hash, book.size())?;
+            }
+        }
+        Ok(())
+    }
+
+    //Records indexed before their location was stored get it filled in by
+    //scanning their block again.
+    pub fn backfill(&self, chain: &dyn ChainSource) -> Result<(), Error> {
+        for height in self.storage.missing_locations()? {
+            let block_hash = chain.block_hash(height)?;
+            let block = chain.block(&block_hash)?;
+            let winner = match select_winner(&block) {
+                (Some(winner), _) => winner,
+                _ => continue
+            };
+            //Only fill in records that are already indexed
+            let data = &winner.1.data;
+            if self.storage.hash(data)?.is_none() && self.storage.did(data)?.is_none() {continue;}
+            println!("[INFO] Backfilling the location of the record from block {}", height);
+            self.storage.add_record(&record(chain, height, &block_hash, &block, winner)?)?;
+        }
+        Ok(())
+    }
+}
+
+//Fetches a block and indexes its winner and losers.
+fn fetch(chain: &dyn ChainSource, block_height: u64) -> Result<FetchedBlock, Error> {
+    let block_hash = chain.block_hash(block_height)?;
+    let block = chain.block(&block_hash)?;
+    let (winner, losers) = select_winner(&block);
+    //Losers are only kept with a winner, except invalid transactions which
+    //lose even in a block without one
+    let winner_txid = winner.as_ref().map(|(txid, _)| txid.to_string());
+    let losers = losers.into_iter()
+        .filter(|(_, loser)| winner_txid.is_some() || loser.invalid)
+        .map(|(txid, loser)| LoserRecord{
+            txid: txid.to_string(),
+            block_height,
+            price: loser.price,
+            data: loser.data,
+            is_hash: loser.is_hash,
+            invalid: loser.invalid,
+            winner: winner_txid.clone().unwrap_or_default(),
+        }).collect();
+    let winner = match winner {
+        Some(winner) => Some(record(chain, block_height, &block_hash, &block, winner)?),
+        None => None
+    };
+    Ok(FetchedBlock{
+        indexed: IndexedBlock{block_height, block_hash: block_hash.to_string(), winner, losers},
+        block,
+    })
+}
+
+//The record the winner of a block publishes, located at its TBPUB output.
+fn record(chain: &dyn ChainSource, block_height: u64, block_hash: &BlockHash, block: &Block, winner: Candidate) -> Result<Record, Error> {
+    let (txid, top_tbpub_tx) = winner;
+    //The fee is worked out from the transaction in the block at hand
+    let fee = match block.txdata.iter().find(|tx| tx.txid() == txid) {
+        Some(tx) => chain.transaction_fee(tx)?,
+        None => None
+    };
+    let location = Some(TxLocation{
+        txid: txid.to_string(),
+        vout: top_tbpub_tx.vout,
+        block_hash: block_hash.to_string(),
+        block_time: block.header.time as u64,
+        fee,
+    });
+    Ok(match top_tbpub_tx.hash_algorithm {
+        Some(algorithm) => Record::Hash(HashRecord{
+            hash: top_tbpub_tx.data,
+            block_height,
+            price: top_tbpub_tx.price,
+            status: HashStatus::Pending,
+            algorithm,
+            location,
+        }),
+        None => Record::RootDID(RootDIDRecord{
+            did: top_tbpub_tx.data,
+            block_height,
+            price: top_tbpub_tx.price,
+            public_key: top_tbpub_tx.public_key,
+            location,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, MockChain};
+    use crate::{Book, Page, hex_encode, MINIMUM_TBPUB_TX_PRICE};
+    use bitcoin::{OutPoint, ScriptBuf, TxOut};
+    use bitcoin::hashes::Hash;
+
+    fn scanner(storage: &StorageHandle) -> Scanner {
+        Scanner::new(&test_util::config(), storage, MINIMUM_BLOCK_HEIGHT, Mempool::default(), ScanProgress::default()).unwrap()
+    }
+
+    #[test]
+    fn scan() {
+        let storage = StorageHandle::memory().unwrap();
+        let blocks = test_util::chain(&[], 0, 10, 1);
+        let chain = MockChain::new(blocks.clone());
+        let mut scanner = scanner(&storage);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &blocks);
+        assert_eq!(index.setting(crate::storage::BLOCK_HEIGHT).unwrap(), Some((MINIMUM_BLOCK_HEIGHT + 10).to_string()));
+        assert_eq!(index.dids().unwrap().len(), 3);
+        assert_eq!(index.pending_hashes().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn reorg() {
+        let storage = StorageHandle::memory().unwrap();
+        let first = test_util::chain(&[], 0, 10, 1);
+        let second = test_util::chain(&first, 6, 12, 2);
+        let chain = MockChain::new(first.clone());
+        let mut scanner = scanner(&storage);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+
+        //The first block of the second chain not building on our tip finds the fork point
+        chain.set_blocks(second.clone());
+        assert!(!scanner.scan_next(&chain).unwrap());
+        let fork_point = MINIMUM_BLOCK_HEIGHT + 5;
+        assert_eq!(scanner.block_height, fork_point + 1);
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &first[..6]);
+        for (block_height, block) in (fork_point + 1..).zip(&first[6..]) {
+            assert_eq!(index.block_hash(block_height).unwrap(), None);
+            assert!(index.losers(block_height).unwrap().is_empty());
+            test_util::assert_not_indexed(index.as_ref(), block);
+        }
+        assert_eq!(index.setting(crate::storage::BLOCK_HEIGHT).unwrap(), Some((fork_point + 1).to_string()));
+
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+        test_util::assert_indexed(index.as_ref(), &second);
+        for block in &first[6..] {
+            test_util::assert_not_indexed(index.as_ref(), block);
+        }
+        assert_eq!(index.dids().unwrap().len(), 4);
+    }
+
+    //A book is only removed once the rescan shows its hash was not mined again.
+    #[test]
+    fn invalid_losers() {
+        let storage = StorageHandle::memory().unwrap();
+        let output = test_util::tbpub_output(crate::FLAG_HASH, &[1; 20], MINIMUM_TBPUB_TX_PRICE);
+        let invalid = test_util::transaction(100, vec![output.clone(), output]);
+        let mut blocks = test_util::chain(&[], 0, 2, 1);
+        blocks.push(test_util::block(blocks[1].block_hash(), 1, vec![invalid.clone()]));
+        let chain = MockChain::new(blocks.clone());
+        let mut scanner = scanner(&storage);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &blocks);
+        let losers = index.losers(MINIMUM_BLOCK_HEIGHT + 2).unwrap();
+        assert_eq!(losers[0].txid, invalid.txid().to_string());
+        assert!(losers[0].invalid && losers[0].winner.is_empty());
+    }
+
+    #[test]
+    fn books_survive_reorg() {
+        let storage = StorageHandle::memory().unwrap();
+        let kept = Book{pages: vec![Page{price: 4, data: "kept".to_string()}]};
+        let dropped = Book{pages: vec![Page{price: 7, data: "dropped".to_string()}]};
+        let kept_tx = test_util::hash_transaction(100, kept.hash(), MINIMUM_TBPUB_TX_PRICE);
+        let dropped_tx = test_util::hash_transaction(101, dropped.hash(), MINIMUM_TBPUB_TX_PRICE);
+        let base = test_util::chain(&[], 0, 3, 1);
+        let mut first = base.clone();
+        first.push(test_util::block(first[2].block_hash(), 1, vec![kept_tx.clone()]));
+        first.push(test_util::block(first[3].block_hash(), 2, vec![dropped_tx]));
+        let mut second = base.clone();
+        second.push(test_util::block(second[2].block_hash(), 3, vec![]));
+        second.push(test_util::block(second[3].block_hash(), 4, vec![kept_tx]));
+        second.push(test_util::block(second[4].block_hash(), 5, vec![]));
+
+        let chain = MockChain::new(first);
+        let mut scanner = scanner(&storage);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+        let books = storage.books().unwrap();
+        books.insert(&hex_encode(kept.hash()), &kept).unwrap();
+        books.insert(&hex_encode(dropped.hash()), &dropped).unwrap();
+
+        chain.set_blocks(second);
+        assert!(!scanner.scan_next(&chain).unwrap());
+        assert!(books.has(&hex_encode(kept.hash())).unwrap());
+        assert!(books.has(&hex_encode(dropped.hash())).unwrap());
+        while scanner.block_height <= chain.block_count().unwrap() {
+            scanner.scan_next(&chain).unwrap();
+        }
+        scanner.collect_garbage().unwrap();
+        assert!(books.has(&hex_encode(kept.hash())).unwrap());
+        assert!(!books.has(&hex_encode(dropped.hash())).unwrap());
+    }
+
+    //A reorg can roll the scan back below the height it started at.
+    #[test]
+    fn progress_rollback() {
+        let progress = ScanProgress::default();
+        assert_eq!(progress.to_json(), json!(null));
+        progress.start(MINIMUM_BLOCK_HEIGHT + 10, MINIMUM_BLOCK_HEIGHT + 20);
+        progress.update(MINIMUM_BLOCK_HEIGHT + 15);
+        progress.rollback(MINIMUM_BLOCK_HEIGHT + 5);
+        let json = progress.to_json();
+        assert_eq!(json["block_height"], MINIMUM_BLOCK_HEIGHT + 5);
+        assert_eq!(json["blocks_remaining"], 16);
+        assert!(json["blocks_per_second"].as_f64().unwrap() >= 0.0);
+        progress.update(MINIMUM_BLOCK_HEIGHT + 21);
+        assert_eq!(progress.to_json()["blocks_remaining"], 0);
+        progress.finish();
+        assert_eq!(progress.to_json(), json!(null));
+    }
+
+    #[test]
+    fn scan_batched_reorg() {
+        let storage = StorageHandle::memory().unwrap();
+        let first = test_util::chain(&[], 0, 10, 1);
+        let second = test_util::chain(&first, 4, 11, 2);
+        let chain = MockChain::new(first.clone());
+        let mut scanner = Scanner::new(&Config{scanbatch: 3, ..test_util::config()}, &storage, MINIMUM_BLOCK_HEIGHT,
+            Mempool::default(), ScanProgress::default()).unwrap();
+        scanner.scan_batched(&chain, chain.block_count().unwrap()).unwrap();
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &first);
+
+        chain.set_blocks(second.clone());
+        while scanner.block_height <= chain.block_count().unwrap() {
+            scanner.scan_batched(&chain, chain.block_count().unwrap()).unwrap();
+        }
+        test_util::assert_indexed(index.as_ref(), &second);
+        for block in &first[4..] {
+            test_util::assert_not_indexed(index.as_ref(), block);
+        }
+    }
+
+    //Once the initial block scan reached the tip new blocks are scanned as
+    //they arrive.
+    #[test]
+    fn follow_tip() {
+        let storage = StorageHandle::memory().unwrap();
+        let blocks = test_util::chain(&[], 0, 12, 1);
+        let chain = MockChain::new(blocks[..8].to_vec());
+        let progress = ScanProgress::default();
+        let mut scanner = Scanner::new(&test_util::config(), &storage, MINIMUM_BLOCK_HEIGHT, Mempool::default(), progress.clone()).unwrap();
+        assert!(scanner.initial_block_scan);
+        scanner.sync(&chain).unwrap();
+        let index = storage.open().unwrap();
+        test_util::assert_indexed(index.as_ref(), &blocks[..8]);
+        assert!(!scanner.initial_block_scan);
+        assert_eq!(index.setting("initial_block_scan").unwrap(), Some("0".to_string()));
+        assert_eq!(progress.to_json(), Value::Null);
+
+        //A block notification wakes the scanner
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        chain.set_blocks(blocks[..10].to_vec());
+        sender.send(()).unwrap();
+        scanner.wait_for_block(&chain, Some(&receiver)).unwrap();
+        assert_eq!(scanner.top_block, MINIMUM_BLOCK_HEIGHT + 9);
+        scanner.sync(&chain).unwrap();
+        test_util::assert_indexed(index.as_ref(), &blocks[..10]);
+
+        //Without notifications a chain that can not wait is polled, backing
+        //off until a block arrives
+        scanner.wait_for_block(&chain, None).unwrap();
+        assert_eq!(scanner.poll_interval, MINIMUM_POLL_INTERVAL * 2);
+        chain.set_blocks(blocks.clone());
+        sender.send(()).unwrap();
+        scanner.wait_for_block(&chain, Some(&receiver)).unwrap();
+        assert_eq!(scanner.poll_interval, MINIMUM_POLL_INTERVAL);
+        scanner.sync(&chain).unwrap();
+        test_util::assert_indexed(index.as_ref(), &blocks);
+        assert_eq!(scanner.block_height, MINIMUM_BLOCK_HEIGHT + 12);
+        //A new scanner carries on following the tip
+        assert!(!Scanner::new(&test_util::config(), &storage, scanner.block_height, Mempool::default(), progress).unwrap().initial_block_scan);
+    }
+
+    //Workers that can not fetch a block leave it to the scan to fetch again.
+    #[test]
+    fn failed_fetches_retried() {
+        let storage = StorageHandle::memory().unwrap();
+        let blocks = test_util::chain(&[], 0, 10, 1);
+        let chain = MockChain::new(blocks.clone());
+        //The workers read a chain that does not exist
+        let config = Config{chainfixture: Some(test_util::temp_dir().join("missing.fixture")), scanworkers: 2, scanbatch: 3,
+            ..test_util::config()};
+        let mut scanner = Scanner::new(&config, &storage, MINIMUM_BLOCK_HEIGHT, Mempool::default(), ScanProgress::default()).unwrap();
+        scanner.scan_pipelined(&chain, chain.block_count().unwrap()).unwrap();
+        test_util::assert_indexed(storage.open().unwrap().as_ref(), &blocks);
+        assert_eq!(scanner.block_height, MINIMUM_BLOCK_HEIGHT + 10);
+    }
+
+    //Fees are the inputs the winner spends less its outputs.
+    #[test]
+    fn fees() {
+        let storage = StorageHandle::memory().unwrap();
+        let funding = test_util::transaction(100, vec![TxOut{value: 50000, script_pubkey: ScriptBuf::new()}]);
+        let mut paying = test_util::hash_transaction(1, [1; 20], MINIMUM_TBPUB_TX_PRICE);
+        paying.input[0].previous_output = OutPoint{txid: funding.txid(), vout: 0};
+        let first = test_util::block(BlockHash::all_zeros(), 1, vec![paying]);
+        //The transaction spent is unknown to the chain
+        let second = test_util::block(first.block_hash(), 2, vec![test_util::hash_transaction(2, [2; 20], MINIMUM_TBPUB_TX_PRICE)]);
+        let chain = MockChain::new(vec![first, second]);
+        chain.add_to_mempool(funding, 0);
+        let mut scanner = scanner(&storage);
+        while scanner.block_height <= chain.block_count().unwrap() {
+            assert!(scanner.scan_next(&chain).unwrap());
+        }
+        let index = storage.open().unwrap();
+        let fee = |hash: [u8; 20]| index.hash(&hex_encode(hash)).unwrap().unwrap().location.unwrap().fee;
+        assert_eq!(fee([1; 20]), Some(50000 - MINIMUM_TBPUB_TX_PRICE));
+        assert_eq!(fee([2; 20]), None);
+    }
+}
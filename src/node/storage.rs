@@ -0,0 +1,228 @@
+use crate::{Error, Config, BookStore, PRICE_PER_BYTE};
+use crate::database::{SqliteStorage, HashRecord, HashStatus, RootDIDRecord, LoserRecord, DIDDocument};
+use crate::memory_storage::MemoryStorage;
+use crate::did::Identity;
+
+use std::path::PathBuf;
+
+//Setting holding the next block to scan
+pub const BLOCK_HEIGHT: &str = "block_height";
+
+//A TBPUB record as indexed from the winner of a block.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Hash(HashRecord),
+    RootDID(RootDIDRecord),
+}
+
+//Everything indexed from one block.
+#[derive(Debug)]
+pub struct IndexedBlock {
+    pub block_height: u64,
+    pub block_hash: String,
+    pub winner: Option<Record>,
+    pub losers: Vec<LoserRecord>,
+}
+
+//Where the node keeps its settings, the records it indexes, the books it
+//shared and the DID documents it resolved. Sqlite is the default, the in
+//memory backend keeps nothing once the node stops.
+pub trait Storage {
+    fn setting(&self, key: &str) -> Result<Option<String>, Error>;
+    fn set_setting(&self, key: &str, value: &str) -> Result<(), Error>;
+
+    fn hash(&self, hash: &str) -> Result<Option<HashRecord>, Error>;
+    fn hash_by_txid(&self, txid: &str) -> Result<Option<HashRecord>, Error>;
+    //Hashes whose book has not been resolved yet, oldest first
+    fn pending_hashes(&self) -> Result<Vec<HashRecord>, Error>;
+    fn set_hash_status(&self, hash: &str, status: HashStatus) -> Result<(), Error>;
+
+    //DIDs are hex encoded like everywhere else in the index
+    fn did(&self, did: &str) -> Result<Option<RootDIDRecord>, Error>;
+    fn did_by_txid(&self, txid: &str) -> Result<Option<RootDIDRecord>, Error>;
+    //Every indexed DID, oldest first
+    fn dids(&self) -> Result<Vec<String>, Error>;
+
+    fn block_hash(&self, block_height: u64) -> Result<Option<String>, Error>;
+    //Losers of a block, best ranked first
+    fn losers(&self, block_height: u64) -> Result<Vec<LoserRecord>, Error>;
+    //Block heights of records indexed before locations were stored
+    fn missing_locations(&self) -> Result<Vec<u64>, Error>;
+
+    //Records already indexed only get their location filled in if it is missing.
+    fn add_record(&self, record: &Record) -> Result<(), Error>;
+    //Commits everything indexed from consecutive blocks together with the next
+    //height to scan, so the blocks are either all fully indexed or not at all.
+    fn add_blocks(&self, blocks: &[IndexedBlock]) -> Result<(), Error>;
+    //Removes everything indexed above the fork point and scans from the block
+    //after it again.
+    fn rollback(&self, fork_point: u64) -> Result<(), Error>;
+
+    //Books served to other Root Nodes, each book is only shared once per peer
+    fn add_share(&self, hash: &str, did: &str) -> Result<(), Error>;
+    fn has_share(&self, hash: &str, did: &str) -> Result<bool, Error>;
+    //Number of books shared with every peer, by hex encoded DID
+    fn share_stats(&self) -> Result<Vec<(String, u64)>, Error>;
+
+    fn did_document(&self, did: &str) -> Result<Option<DIDDocument>, Error>;
+    fn set_did_document(&self, did: &str, document: &DIDDocument) -> Result<(), Error>;
+
+    fn add_block(&self, block: &IndexedBlock) -> Result<(), Error> {
+        self.add_blocks(std::slice::from_ref(block))
+    }
+
+    //Once a book is resolved its size decides whether the hash paid enough,
+    //at least PRICE_PER_BYTE for every byte of the book.
+    fn resolve_hash(&self, hash: &str, book_size: u64) -> Result<Option<HashStatus>, Error> {
+        let record = match self.hash(hash)? {
+            Some(record) => record,
+            None => return Ok(None)
+        };
+        let status = match record.price >= book_size * PRICE_PER_BYTE {
+            true => HashStatus::Valid,
+            false => HashStatus::Underpaid
+        };
+        self.set_hash_status(hash, status)?;
+        Ok(Some(status))
+    }
+}
+
+//The storage backend picked with storage=.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Sqlite,
+    Memory,
+}
+
+impl StorageBackend {
+    pub fn from_str(name: &str) -> Result<StorageBackend, Error> {
+        match name {
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(Error::UnknownArgument(format!("storage={}", name)))
+        }
+    }
+}
+
+//Everything the node keeps, handed to every thread that needs it. Sqlite
+//connections can not be shared between threads so each opens its own, the
+//in memory backend is shared by every clone of the handle and also keeps the
+//books and the identity of the node off disk.
+#[derive(Clone)]
+pub enum StorageHandle {
+    Sqlite(PathBuf),
+    Memory(MemoryStorage, BookStore, Box<Identity>),
+}
+
+impl StorageHandle {
+    pub fn new(config: &Config) -> Result<StorageHandle, Error> {
+        match config.storage {
+            StorageBackend::Sqlite => Ok(StorageHandle::Sqlite(config.datadir.clone())),
+            StorageBackend::Memory => StorageHandle::memory()
+        }
+    }
+
+    pub fn memory() -> Result<StorageHandle, Error> {
+        Ok(StorageHandle::Memory(MemoryStorage::default(), BookStore::memory(), Box::new(Identity::generate()?)))
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Storage>, Error> {
+        match self {
+            StorageHandle::Sqlite(datadir) => Ok(Box::new(SqliteStorage::new(datadir)?)),
+            StorageHandle::Memory(memory, _, _) => Ok(Box::new(memory.clone()))
+        }
+    }
+
+    pub fn books(&self) -> Result<BookStore, Error> {
+        match self {
+            StorageHandle::Sqlite(datadir) => BookStore::files(datadir),
+            StorageHandle::Memory(_, books, _) => Ok(books.clone())
+        }
+    }
+
+    pub fn identity(&self) -> Result<Identity, Error> {
+        match self {
+            StorageHandle::Sqlite(datadir) => Identity::load(datadir),
+            StorageHandle::Memory(_, _, identity) => Ok(identity.as_ref().clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{TxLocation, LoserRecord};
+    use crate::book::HashAlgorithm;
+    use crate::test_util;
+
+    fn hash_record(hash: &str, block_height: u64, location: Option<TxLocation>) -> Record {
+        Record::Hash(HashRecord{hash: hash.to_string(), block_height, price: 100, status: HashStatus::Pending,
+            algorithm: HashAlgorithm::Sha1, location})
+    }
+
+    //Both backends must keep the same index.
+    fn exercise(storage: &dyn Storage) {
+        let location = TxLocation{txid: "aa".to_string(), vout: 1, block_hash: "bb".to_string(), block_time: 5, fee: Some(7)};
+        storage.add_record(&hash_record("01", 10, None)).unwrap();
+        assert_eq!(storage.missing_locations().unwrap(), vec![10]);
+        //A record already indexed only gets its location filled in
+        storage.add_record(&hash_record("01", 11, Some(location.clone()))).unwrap();
+        let record = storage.hash_by_txid("aa").unwrap().unwrap();
+        assert_eq!(record.block_height, 10);
+        assert_eq!(record.location.unwrap().fee, Some(7));
+        assert!(storage.missing_locations().unwrap().is_empty());
+
+        storage.add_blocks(&[
+            IndexedBlock{block_height: 11, block_hash: "b11".to_string(), winner: Some(hash_record("02", 11, None)), losers: vec![]},
+            IndexedBlock{block_height: 12, block_hash: "b12".to_string(), winner: None, losers: vec![LoserRecord{
+                txid: "cc".to_string(), block_height: 12, price: 300, data: "03".to_string(), is_hash: false,
+                invalid: true, winner: String::new()}]},
+        ]).unwrap();
+        let loser = &storage.losers(12).unwrap()[0];
+        assert!(loser.invalid && loser.winner.is_empty());
+        assert_eq!(storage.setting(BLOCK_HEIGHT).unwrap(), Some("13".to_string()));
+        assert_eq!(storage.block_hash(12).unwrap(), Some("b12".to_string()));
+        assert_eq!(storage.resolve_hash("02", 100).unwrap(), Some(HashStatus::Valid));
+        assert_eq!(storage.resolve_hash("01", 101).unwrap(), Some(HashStatus::Underpaid));
+        assert!(storage.pending_hashes().unwrap().is_empty());
+
+        storage.rollback(10).unwrap();
+        assert_eq!(storage.setting(BLOCK_HEIGHT).unwrap(), Some("11".to_string()));
+        assert!(storage.hash("02").unwrap().is_none());
+        assert!(storage.hash("01").unwrap().is_some());
+        assert!(storage.block_hash(11).unwrap().is_none());
+
+        storage.add_share("01", "d1").unwrap();
+        storage.add_share("01", "d1").unwrap();
+        storage.add_share("02", "d1").unwrap();
+        assert!(storage.has_share("01", "d1").unwrap());
+        assert!(!storage.has_share("01", "d2").unwrap());
+        assert_eq!(storage.share_stats().unwrap(), vec![("d1".to_string(), 2)]);
+
+        let document = DIDDocument{endpoint: Some("http://a".to_string()), public_key: None, sequence: 3, fetched: 4};
+        storage.set_did_document("did", &document).unwrap();
+        assert_eq!(storage.did_document("did").unwrap().unwrap().sequence, 3);
+        assert!(storage.did_document("other").unwrap().is_none());
+    }
+
+    #[test]
+    fn memory() {
+        exercise(StorageHandle::memory().unwrap().open().unwrap().as_ref());
+    }
+
+    #[test]
+    fn sqlite() {
+        let datadir = test_util::temp_dir();
+        exercise(StorageHandle::Sqlite(datadir.clone()).open().unwrap().as_ref());
+        std::fs::remove_dir_all(datadir).unwrap();
+    }
+
+    #[test]
+    fn memory_handles_share_everything() {
+        let handle = StorageHandle::memory().unwrap();
+        handle.clone().open().unwrap().set_setting("key", "value").unwrap();
+        assert_eq!(handle.open().unwrap().setting("key").unwrap(), Some("value".to_string()));
+        assert_eq!(handle.clone().identity().unwrap().did(), handle.identity().unwrap().did());
+        assert!(handle.books().unwrap().list().unwrap().is_empty());
+    }
+}
@@ -1,48 +1,380 @@
 use crate::Transaction;
-use crate::hex_encode;
-use crate::{MINIMUM_TBPUB_TX_PRICE, TBPUB, OP_RETURN};
-
-pub fn did_from_bytes(data: &[u8]) -> Option<&str> {
-    let did = match std::str::from_utf8(data) {
-        Err(_) => return None,
-        Ok(s) => s
-    };
-    let mut split = did.split(":");
-    if split.next() == Some("did") && split.next() == Some("dht") {
-        let suffix = split.next();
-        if suffix.is_some() && suffix.unwrap().len() == 52 {
-            return suffix;
+use crate::{hex_encode, hex_decode};
+use crate::{MINIMUM_TBPUB_TX_PRICE, TBPUB, FLAG_HASH, FLAG_DID, FLAG_METHOD_DID, FLAG_SHA256_HASH};
+use crate::book::{HashAlgorithm, BOOK_HASH_SIZE, SHA256_BOOK_HASH_SIZE};
+use crate::did_method::{self, DIDMethod, DHTMethod};
+
+use bitcoin::{Block, Script, Txid};
+use std::cmp::Ordering;
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::Instruction;
+
+//The method of a published DID, returns None for anything but a DID well
+//formed for one of the supported methods.
+pub fn did_from_bytes(data: &[u8]) -> Option<&'static dyn DIDMethod> {
+    did_method::method(std::str::from_utf8(data).ok()?)
+}
+
+//The data an OP_RETURN script carries. Valid scripts are OP_RETURN followed
+//only by pushes, made with any push opcode including OP_PUSHDATA1/2/4, and
+//the data of all the pushes is concatenated. Scripts with any other opcode
+//after OP_RETURN, including OP_1 to OP_16, or a truncated push carry nothing.
+pub fn op_return_data(script: &Script) -> Option<Vec<u8>> {
+    let mut instructions = script.instructions();
+    match instructions.next()? {
+        Ok(Instruction::Op(opcode)) if opcode == OP_RETURN => (),
+        _ => return None
+    }
+    let mut data = Vec::new();
+    for instruction in instructions {
+        match instruction.ok()? {
+            Instruction::PushBytes(bytes) => data.extend_from_slice(bytes.as_bytes()),
+            Instruction::Op(_) => return None
+        }
+    }
+    Some(data)
+}
+
+//Payloads are the TBPUB magic followed by a version byte with the high bit
+//set, the flag and the data. v0 payloads predate versions and have the flag,
+//which always has the high bit clear, straight after the magic. A version
+//byte for v0 would give it a second encoding so it is invalid.
+const VERSION_MARKER: u8 = 0x80;
+pub const PROTOCOL_VERSION: u8 = 0;
+
+pub struct Payload<'a> {
+    pub version: u8,
+    pub flag: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    pub fn parse(payload: &'a [u8]) -> Option<Payload<'a>> {
+        let payload = payload.strip_prefix(hex_decode(TBPUB).ok()?.as_slice())?;
+        let (&first, rest) = payload.split_first()?;
+        if first & VERSION_MARKER == 0 {
+            return Some(Payload{version: 0, flag: first, data: rest});
         }
+        let version = first & !VERSION_MARKER;
+        if version == 0 {return None;}
+        let (&flag, data) = rest.split_first()?;
+        Some(Payload{version, flag, data})
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = hex_decode(TBPUB).unwrap();
+        if self.version != 0 {payload.push(VERSION_MARKER | self.version);}
+        payload.push(self.flag);
+        payload.extend_from_slice(self.data);
+        payload
+    }
+
+    //Hex encoded payload at the current protocol version, as broadcast.
+    pub fn hex(flag: u8, data: &[u8]) -> String {
+        hex_encode(Payload{version: PROTOCOL_VERSION, flag, data}.encode())
+    }
+}
+
+//(data, hash_algorithm, public_key) of a v0 payload.
+fn parse_v0(flag: u8, data: &[u8]) -> Option<(Vec<u8>, Option<HashAlgorithm>, Option<String>)> {
+    match flag {
+        FLAG_HASH if data.len() == BOOK_HASH_SIZE => Some((data.to_vec(), Some(HashAlgorithm::Sha1), None)),
+        FLAG_SHA256_HASH if data.len() == SHA256_BOOK_HASH_SIZE => Some((data.to_vec(), Some(HashAlgorithm::Sha256), None)),
+        //FLAG_DID predates the other methods and is always did:dht
+        FLAG_DID | FLAG_METHOD_DID => {
+            let (id, did) = match flag {
+                FLAG_DID => (DHTMethod.id(), data),
+                _ => (*data.first()?, data.get(1..)?)
+            };
+            let method = did_from_bytes(did).filter(|method| method.id() == id)?;
+            let public_key = method.public_key(std::str::from_utf8(did).ok()?);
+            Some((did.to_vec(), None, public_key.map(|public_key| hex_encode(public_key.as_bytes()))))
+        },
+        _ => None
     }
-    None
 }
 
 #[non_exhaustive]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TBPubTransaction {
     pub price: u64,
+    //Index of the TBPUB output
+    pub vout: u32,
     pub data: String,
-    pub is_hash: bool
+    pub is_hash: bool,
+    //Algorithm of the hash, None for DIDs
+    pub hash_algorithm: Option<HashAlgorithm>,
+    //Hex encoded key of the DID, None for hashes and DIDs whose key is only
+    //in their DID document
+    pub public_key: Option<String>,
+    //Set for transactions with more than one TBPUB output, which never win
+    pub invalid: bool,
 }
 
 impl TBPubTransaction {
     pub fn from_transaction(tx: &Transaction) -> Option<TBPubTransaction> {
-        let mut result: Option<TBPubTransaction> = None;
-        for output in &tx.output {
-            let output_script = output.script_pubkey.to_bytes();
-            if output_script.len() > 8 {
-                if output_script[0] == OP_RETURN && hex_encode(&output_script[2..7]) == TBPUB {
-                    if result.is_some() {return None;}
-                    if output.value < MINIMUM_TBPUB_TX_PRICE {return None;}
-                    let (data, is_hash) = match output_script[7] {
-                        0x00 if output_script.len() == 28 => (output_script[8..28].to_vec(), true),
-                        0x01 if did_from_bytes(&output_script[8..]).is_some() => (output_script[8..].to_vec(), false),
-                        _ => return None
-                    };
-                    result = Some(TBPubTransaction{price: output.value, data: hex_encode(data), is_hash: is_hash});
-                }
+        Self::parse(tx).filter(|tbpub_tx| !tbpub_tx.invalid)
+    }
+
+    //Like from_transaction but a transaction with more than one TBPUB output
+    //is returned marked invalid, priced at all its TBPUB outputs together and
+    //with the raw data of the first one.
+    pub fn parse(tx: &Transaction) -> Option<TBPubTransaction> {
+        let outputs: Vec<(usize, u64, Vec<u8>)> = tx.output.iter().enumerate()
+            .filter_map(|(vout, output)| {
+                let data = op_return_data(&output.script_pubkey)?;
+                (hex_encode(data.get(..TBPUB.len() / 2).unwrap_or_default()) == TBPUB)
+                    .then_some((vout, output.value, data))
+            })
+            .collect();
+        match outputs.as_slice() {
+            [] => None,
+            [(vout, price, data)] => {
+                if *price < MINIMUM_TBPUB_TX_PRICE {return None;}
+                let payload = Payload::parse(data)?;
+                //Versions this node does not know yet are ignored
+                let (data, hash_algorithm, public_key) = match payload.version {
+                    0 => parse_v0(payload.flag, payload.data)?,
+                    _ => return None
+                };
+                Some(TBPubTransaction{
+                    price: *price,
+                    vout: *vout as u32,
+                    data: hex_encode(data),
+                    is_hash: hash_algorithm.is_some(),
+                    hash_algorithm,
+                    public_key,
+                    invalid: false
+                })
+            },
+            [(vout, _, data), ..] => {
+                let price = outputs.iter().map(|(_, price, _)| price).sum();
+                if price < MINIMUM_TBPUB_TX_PRICE {return None;}
+                Some(TBPubTransaction{
+                    price,
+                    vout: *vout as u32,
+                    data: hex_encode(data),
+                    is_hash: false,
+                    hash_algorithm: None,
+                    public_key: None,
+                    invalid: true
+                })
             }
         }
-        result
+    }
+}
+
+//A TBPUB Transaction competing for a block and its txid.
+pub type Candidate = (Txid, TBPubTransaction);
+
+//Order of TBPUB Transactions competing for the same block, best first. The
+//highest price wins and equal prices go to the lowest txid in its usual hex
+//form, so every Root Node picks the same winner whatever the order of the
+//transactions in the block.
+pub fn rank(a: (&Txid, u64), b: (&Txid, u64)) -> Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+}
+
+//The consensus rule for a block: of all its TBPUB Transactions only the
+//valid one ranked first wins, the rest are returned as losers in rank order.
+//Transactions with more than one TBPUB output never win but are returned as
+//losers marked invalid, those with an invalid payload never take part.
+pub fn select_winner(block: &Block) -> (Option<Candidate>, Vec<Candidate>) {
+    let mut candidates: Vec<Candidate> = block.txdata.iter()
+        .filter_map(|tx| TBPubTransaction::parse(tx).map(|tbpub_tx| (tx.txid(), tbpub_tx)))
+        .collect();
+    candidates.sort_by(|(a_txid, a), (b_txid, b)| rank((a_txid, a.price), (b_txid, b.price)));
+    let winner = candidates.iter().position(|(_, tbpub_tx)| !tbpub_tx.invalid)
+        .map(|index| candidates.remove(index));
+    (winner, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use bitcoin::{BlockHash, ScriptBuf, TxOut};
+    use bitcoin::script::PushBytesBuf;
+    use bitcoin::hashes::Hash;
+
+    const HASH: &str = "0102030405060708090a0b0c0d0e0f1011121314";
+
+    //The 26 byte Book Hash payload P of the table in the README.
+    fn payload() -> String {
+        format!("{}{:02x}{}", TBPUB, FLAG_HASH, HASH)
+    }
+
+    //Checks a row of the table: the script, with P standing for the payload,
+    //and whether it carries P.
+    fn check(script: &str, carries_payload: bool) {
+        let script = ScriptBuf::from_bytes(hex_decode(script.replace(' ', "").replace('P', &payload())).unwrap());
+        let expected = carries_payload.then(|| hex_decode(payload()).unwrap());
+        assert_eq!(op_return_data(&script), expected);
+        let tx = test_util::transaction(0, vec![TxOut{value: MINIMUM_TBPUB_TX_PRICE, script_pubkey: script}]);
+        let tbpub_tx = TBPubTransaction::from_transaction(&tx);
+        assert_eq!(tbpub_tx.as_ref().map(|tbpub_tx| tbpub_tx.data.as_str()), carries_payload.then_some(HASH));
+        if let Some(tbpub_tx) = tbpub_tx {
+            assert!(tbpub_tx.is_hash);
+            assert_eq!(tbpub_tx.vout, 0);
+            assert_eq!(tbpub_tx.price, MINIMUM_TBPUB_TX_PRICE);
+        }
+    }
+
+    #[test]
+    fn single_push() {
+        check("6a 1a P", true);
+    }
+
+    #[test]
+    fn pushdata1() {
+        check("6a 4c 1a P", true);
+    }
+
+    #[test]
+    fn pushdata2() {
+        check("6a 4d 1a00 P", true);
+    }
+
+    #[test]
+    fn pushdata4() {
+        check("6a 4e 1a000000 P", true);
+    }
+
+    #[test]
+    fn split_pushes() {
+        let rest = &payload()[TBPUB.len()..];
+        check(&format!("6a 05 {} 15 {}", TBPUB, rest), true);
+    }
+
+    #[test]
+    fn empty_push() {
+        check("6a 00 1a P", true);
+    }
+
+    #[test]
+    fn truncated_push() {
+        check("6a 1b P", false);
+    }
+
+    #[test]
+    fn trailing_opcode() {
+        check("6a 1a P 51", false);
+    }
+
+    #[test]
+    fn leading_opcode() {
+        check("6a 51 P", false);
+    }
+
+    #[test]
+    fn not_op_return() {
+        check("1a P", false);
+    }
+
+    //A transaction with two TBPUB outputs loses whatever it pays, even in a
+    //block with no valid transaction.
+    #[test]
+    fn two_outputs_lose() {
+        let hash = hex_decode(HASH).unwrap();
+        let two_outputs = test_util::transaction(0, vec![
+            test_util::tbpub_output(FLAG_HASH, &hash, MINIMUM_TBPUB_TX_PRICE * 5),
+            test_util::tbpub_output(FLAG_HASH, &hash, MINIMUM_TBPUB_TX_PRICE * 5),
+        ]);
+        let valid = test_util::transaction(1, vec![test_util::tbpub_output(FLAG_HASH, &hash, MINIMUM_TBPUB_TX_PRICE)]);
+        assert!(TBPubTransaction::from_transaction(&two_outputs).is_none());
+
+        let block = test_util::block(BlockHash::all_zeros(), 0, vec![two_outputs.clone(), valid.clone()]);
+        let (winner, losers) = select_winner(&block);
+        assert_eq!(winner.unwrap().0, valid.txid());
+        let (txid, loser) = &losers[0];
+        assert_eq!((*txid, loser.price, loser.invalid), (two_outputs.txid(), MINIMUM_TBPUB_TX_PRICE * 10, true));
+        assert_eq!(loser.data, hex_encode(Payload{version: PROTOCOL_VERSION, flag: FLAG_HASH, data: &hash}.encode()));
+
+        let block = test_util::block(BlockHash::all_zeros(), 0, vec![two_outputs]);
+        let (winner, losers) = select_winner(&block);
+        assert!(winner.is_none());
+        assert_eq!(losers.len(), 1);
+    }
+
+    //FLAG_METHOD_DID payloads only count when the method id names the method
+    //of the DID that follows it.
+    #[test]
+    fn method_ids() {
+        let parse = |id: u8, did: &str| {
+            let output = test_util::tbpub_output(FLAG_METHOD_DID, &[&[id], did.as_bytes()].concat(), MINIMUM_TBPUB_TX_PRICE);
+            TBPubTransaction::from_transaction(&test_util::transaction(0, vec![output]))
+        };
+        let key = "did:key:z6MktwupdmLXVVqTzCw4i46r4uGyosGXRnR3XjN4Zq7oMMsw";
+        let web = "did:web:node.example";
+        let dht = test_util::did(1);
+        let tbpub_tx = parse(1, key).unwrap();
+        assert_eq!(tbpub_tx.data, hex_encode(key));
+        assert_eq!(tbpub_tx.public_key.as_deref(), Some("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"));
+        assert!(!tbpub_tx.is_hash);
+        assert!(parse(2, web).is_some_and(|tbpub_tx| tbpub_tx.public_key.is_none()));
+        assert!(parse(0, &dht).is_some());
+        assert!(parse(2, key).is_none());
+        assert!(parse(1, web).is_none());
+        assert!(parse(1, &dht).is_none());
+        assert!(parse(3, web).is_none());
+        assert!(parse(1, "").is_none());
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let hash = hex_decode(HASH).unwrap();
+        for (version, flag) in [(0, FLAG_HASH), (0, FLAG_METHOD_DID), (1, FLAG_HASH), (1, 0x80), (127, 0xff)] {
+            let encoded = Payload{version, flag, data: &hash}.encode();
+            let payload = Payload::parse(&encoded).unwrap();
+            assert_eq!((payload.version, payload.flag, payload.data), (version, flag, hash.as_slice()));
+        }
+        assert_eq!(hex_encode(Payload{version: 0, flag: FLAG_HASH, data: &hash}.encode()), payload());
+        assert_eq!(hex_encode(Payload{version: 1, flag: FLAG_HASH, data: &[]}.encode()), format!("{}8100", TBPUB));
+    }
+
+    //v0 has the one encoding without a version byte.
+    #[test]
+    fn explicit_v0_rejected() {
+        let explicit = format!("{}80{:02x}{}", TBPUB, FLAG_HASH, HASH);
+        assert!(Payload::parse(&hex_decode(&explicit).unwrap()).is_none());
+        let script = ScriptBuf::new_op_return(&PushBytesBuf::try_from(hex_decode(&explicit).unwrap()).unwrap());
+        let tx = test_util::transaction(0, vec![TxOut{value: MINIMUM_TBPUB_TX_PRICE, script_pubkey: script}]);
+        assert!(TBPubTransaction::from_transaction(&tx).is_none());
+        //Nothing but the magic and a version byte
+        assert!(Payload::parse(&hex_decode(format!("{}81", TBPUB)).unwrap()).is_none());
+        assert!(Payload::parse(&hex_decode(TBPUB).unwrap()).is_none());
+    }
+
+    //Versions this node does not know are ignored, however valid their data
+    //would be as v0.
+    #[test]
+    fn unknown_versions_ignored() {
+        let hash = hex_decode(HASH).unwrap();
+        for version in [1, 2, 127] {
+            let payload = Payload{version, flag: FLAG_HASH, data: &hash}.encode();
+            let script = ScriptBuf::new_op_return(&PushBytesBuf::try_from(payload).unwrap());
+            let tx = test_util::transaction(0, vec![TxOut{value: MINIMUM_TBPUB_TX_PRICE, script_pubkey: script}]);
+            assert!(TBPubTransaction::from_transaction(&tx).is_none());
+        }
+        let tx = test_util::transaction(0, vec![test_util::tbpub_output(FLAG_HASH, &hash, MINIMUM_TBPUB_TX_PRICE)]);
+        assert_eq!(TBPubTransaction::from_transaction(&tx).unwrap().data, HASH);
+    }
+
+    //The flag fixes the size of the hash and the algorithm it was made with.
+    #[test]
+    fn hash_sizes() {
+        let parse = |flag: u8, hash: &[u8]| {
+            TBPubTransaction::from_transaction(&test_util::transaction(0, vec![test_util::tbpub_output(flag, hash, MINIMUM_TBPUB_TX_PRICE)]))
+        };
+        let sha256 = [3; SHA256_BOOK_HASH_SIZE];
+        let sha1 = [1; BOOK_HASH_SIZE];
+        let tbpub_tx = parse(FLAG_SHA256_HASH, &sha256).unwrap();
+        assert_eq!(tbpub_tx.data, hex_encode(sha256));
+        assert_eq!(tbpub_tx.hash_algorithm, Some(HashAlgorithm::Sha256));
+        assert!(tbpub_tx.is_hash);
+        assert_eq!(parse(FLAG_HASH, &sha1).unwrap().hash_algorithm, Some(HashAlgorithm::Sha1));
+        assert!(parse(FLAG_SHA256_HASH, &sha1).is_none());
+        assert!(parse(FLAG_HASH, &sha256).is_none());
+        assert!(parse(FLAG_SHA256_HASH, &sha256[1..]).is_none());
     }
 }
@@ -0,0 +1,189 @@
+use crate::{Error, Config, MINIMUM_BLOCK_HEIGHT, MINIMUM_TBPUB_TX_PRICE, FLAG_HASH, FLAG_DID};
+use crate::chain_source::ChainSource;
+use crate::did::{zbase32_encode, DID_DHT_PREFIX};
+use crate::storage::{Storage, StorageBackend};
+use crate::tbpub_transaction::{select_winner, Payload, PROTOCOL_VERSION};
+
+use bitcoin::{Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Txid};
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::hash_types::TxMerkleNode;
+use bitcoin::hashes::Hash;
+use bitcoin::script::PushBytesBuf;
+use ed25519_dalek::SigningKey;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+//Helpers shared by the tests: configs that keep everything in memory, a
+//chain served from memory like a mocked RPC and blocks of TBPUB Transactions.
+
+//A datadir nothing may touch, in memory nodes never need one.
+pub fn config() -> Config {
+    Config{storage: StorageBackend::Memory, ..Config::with_datadir(PathBuf::from("/nonexistent/tbpub"))}
+}
+
+//An empty directory of its own for every call.
+pub fn temp_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("tbpub-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+pub fn tbpub_output(flag: u8, data: &[u8], price: u64) -> TxOut {
+    let payload = Payload{version: PROTOCOL_VERSION, flag, data}.encode();
+    TxOut{value: price, script_pubkey: ScriptBuf::new_op_return(&PushBytesBuf::try_from(payload).unwrap())}
+}
+
+//The seed only makes the txid unique.
+pub fn transaction(seed: u32, output: Vec<TxOut>) -> Transaction {
+    let input = vec![TxIn{previous_output: OutPoint{txid: Txid::all_zeros(), vout: seed}, ..TxIn::default()}];
+    Transaction{version: 2, lock_time: LockTime::ZERO, input, output}
+}
+
+pub fn hash_transaction(seed: u32, hash: [u8; 20], price: u64) -> Transaction {
+    transaction(seed, vec![tbpub_output(FLAG_HASH, &hash, price)])
+}
+
+//The did:dht identifier of the key made of the seed.
+pub fn did(seed: u8) -> String {
+    format!("{}{}", DID_DHT_PREFIX, zbase32_encode(SigningKey::from_bytes(&[seed; 32]).verifying_key().as_bytes()))
+}
+
+pub fn block(prev_blockhash: BlockHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+    let header = Header{version: Version::TWO, prev_blockhash, merkle_root: TxMerkleNode::all_zeros(), time: nonce,
+        bits: CompactTarget::from_consensus(0x207fffff), nonce};
+    Block{header, txdata}
+}
+
+//The blocks of the base up to the fork point followed by blocks of a branch
+//up to the length, the first at MINIMUM_BLOCK_HEIGHT. Every new block has a
+//winning hash or, every third block, a winning DID and a losing hash.
+pub fn chain(base: &[Block], fork: usize, length: usize, branch: u8) -> Vec<Block> {
+    let mut blocks = base[..fork].to_vec();
+    for index in fork..length {
+        let prev_blockhash = blocks.last().map(|block| block.block_hash()).unwrap_or(BlockHash::all_zeros());
+        let seed = (branch as u32) << 16 | index as u32;
+        let mut hash = [branch; 20];
+        hash[..4].copy_from_slice(&(index as u32).to_be_bytes());
+        let mut txdata = vec![hash_transaction(seed, hash, MINIMUM_TBPUB_TX_PRICE + index as u64)];
+        if index % 3 == 2 {
+            let did = did(branch.wrapping_mul(100).wrapping_add(index as u8));
+            txdata.push(transaction(seed | 1 << 24, vec![tbpub_output(FLAG_DID, did.as_bytes(), MINIMUM_TBPUB_TX_PRICE * 2)]));
+        }
+        blocks.push(block(prev_blockhash, seed, txdata));
+    }
+    blocks
+}
+
+//Checks every block is indexed at its height with its winner and losers.
+pub fn assert_indexed(storage: &dyn Storage, blocks: &[Block]) {
+    for (index, block) in blocks.iter().enumerate() {
+        let block_height = MINIMUM_BLOCK_HEIGHT + index as u64;
+        assert_eq!(storage.block_hash(block_height).unwrap(), Some(block.block_hash().to_string()));
+        let (winner, losers) = select_winner(block);
+        if let Some((txid, _)) = winner {
+            let txid = txid.to_string();
+            let indexed = match storage.hash_by_txid(&txid).unwrap() {
+                Some(record) => record.block_height,
+                None => storage.did_by_txid(&txid).unwrap().expect("winner is indexed").block_height
+            };
+            assert_eq!(indexed, block_height);
+        }
+        let indexed: Vec<String> = storage.losers(block_height).unwrap().into_iter().map(|loser| loser.txid).collect();
+        assert_eq!(indexed, losers.iter().map(|(txid, _)| txid.to_string()).collect::<Vec<String>>());
+    }
+}
+
+//Checks nothing a block published is indexed.
+pub fn assert_not_indexed(storage: &dyn Storage, block: &Block) {
+    for tx in &block.txdata {
+        let txid = tx.txid().to_string();
+        assert!(storage.hash_by_txid(&txid).unwrap().is_none());
+        assert!(storage.did_by_txid(&txid).unwrap().is_none());
+    }
+}
+
+//Serves canned responses keyed by "<method> <path>" on a local port until the
+//test ends and returns its url, anything else is a 404. The bodies of the
+//requests are sent back to the test.
+pub fn http_server(routes: HashMap<String, (u16, Vec<u8>)>) -> (String, std::sync::mpsc::Receiver<(String, Vec<u8>)>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move|| {
+        for mut request in server.incoming_requests() {
+            let route = format!("{} {}", request.method(), request.url());
+            let mut body = Vec::new();
+            let _ = request.as_reader().read_to_end(&mut body);
+            let (status, response) = routes.get(&route).cloned().unwrap_or((404, vec![]));
+            let _ = sender.send((route, body));
+            let _ = request.respond(tiny_http::Response::from_data(response).with_status_code(status));
+        }
+    });
+    (url, receiver)
+}
+
+#[derive(Default)]
+struct MockState {
+    blocks: Vec<Block>,
+    mempool: HashMap<Txid, (Transaction, u64)>,
+}
+
+//A chain answering from memory the way Bitcoin Core's RPC would, the first
+//block is at MINIMUM_BLOCK_HEIGHT. Clones serve the same chain.
+#[derive(Clone, Default)]
+pub struct MockChain {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockChain {
+    pub fn new(blocks: Vec<Block>) -> MockChain {
+        let chain = MockChain::default();
+        chain.set_blocks(blocks);
+        chain
+    }
+
+    //Replacing the blocks above some height is a reorg.
+    pub fn set_blocks(&self, blocks: Vec<Block>) {
+        self.state.lock().unwrap().blocks = blocks;
+    }
+
+    pub fn add_to_mempool(&self, tx: Transaction, fee: u64) {
+        self.state.lock().unwrap().mempool.insert(tx.txid(), (tx, fee));
+    }
+}
+
+impl ChainSource for MockChain {
+    fn block_count(&self) -> Result<u64, Error> {
+        Ok(MINIMUM_BLOCK_HEIGHT + self.state.lock().unwrap().blocks.len() as u64 - 1)
+    }
+
+    fn block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        let state = self.state.lock().unwrap();
+        block_height.checked_sub(MINIMUM_BLOCK_HEIGHT)
+            .and_then(|index| state.blocks.get(index as usize))
+            .map(|block| block.block_hash())
+            .ok_or(Error::ChainSource(format!("No block at height {}", block_height)))
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<Block, Error> {
+        self.state.lock().unwrap().blocks.iter().find(|block| block.block_hash() == *block_hash).cloned()
+            .ok_or(Error::ChainSource(format!("No block {}", block_hash)))
+    }
+
+    fn mempool(&self) -> Result<Vec<Txid>, Error> {
+        Ok(self.state.lock().unwrap().mempool.keys().copied().collect())
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<(u64, u64)>, Error> {
+        Ok(self.state.lock().unwrap().mempool.get(txid).map(|(tx, fee)| (*fee, tx.vsize() as u64)))
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        Ok(self.state.lock().unwrap().mempool.get(txid).map(|(tx, _)| tx.clone()))
+    }
+}
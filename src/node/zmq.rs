@@ -0,0 +1,230 @@
+use crate::Error;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+//Minimal ZMTP 3.0 SUB socket using the NULL mechanism, enough to receive the
+//notifications published by Bitcoin Core's -zmqpub* options.
+//https://rfc.zeromq.org/spec/23/
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+//The largest message Bitcoin Core publishes is a raw block, which can not be
+//larger than 4,000,000 bytes. Bitcoin Core sends three frames per message.
+const MAXIMUM_FRAME_SIZE: u64 = 4_000_000;
+const MAXIMUM_FRAMES: usize = 16;
+
+pub struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    pub fn connect(endpoint: &str, topic: &str) -> Result<Subscriber, Error> {
+        let address = endpoint.strip_prefix("tcp://").ok_or(Error::ZMQProtocol(format!(
+                    "Unsupported endpoint {}", endpoint)))?;
+        let mut subscriber = Subscriber{stream: TcpStream::connect(address)?};
+        subscriber.handshake()?;
+        //ZMTP 3.0 subscriptions are sent as a message starting with 0x01
+        let mut subscription = vec![0x01];
+        subscription.extend_from_slice(topic.as_bytes());
+        subscriber.send_frame(0x00, &subscription)?;
+        Ok(subscriber)
+    }
+
+    fn handshake(&mut self) -> Result<(), Error> {
+        let mut greeting = [0u8; 64];
+        greeting[0] = 0xff;
+        greeting[9] = 0x7f;
+        greeting[10] = 3;
+        greeting[11] = 0;
+        greeting[12..16].copy_from_slice(b"NULL");
+        self.stream.write_all(&greeting)?;
+
+        let mut peer_greeting = [0u8; 64];
+        self.stream.read_exact(&mut peer_greeting)?;
+        if peer_greeting[0] != 0xff || peer_greeting[9] != 0x7f || peer_greeting[10] < 3 {
+            return Err(Error::ZMQProtocol("Invalid greeting".to_string()));
+        }
+        if &peer_greeting[12..16] != b"NULL" {
+            return Err(Error::ZMQProtocol("Unsupported security mechanism".to_string()));
+        }
+
+        let mut ready = vec![5];
+        ready.extend_from_slice(b"READY");
+        ready.push(11);
+        ready.extend_from_slice(b"Socket-Type");
+        ready.extend_from_slice(&3u32.to_be_bytes());
+        ready.extend_from_slice(b"SUB");
+        self.send_frame(FLAG_COMMAND, &ready)?;
+
+        let (flags, body) = self.recv_frame()?;
+        if flags & FLAG_COMMAND == 0 || !body.starts_with(b"\x05READY") {
+            return Err(Error::ZMQProtocol("Expected READY command".to_string()));
+        }
+        Ok(())
+    }
+
+    fn send_frame(&mut self, flags: u8, body: &[u8]) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(body.len() + 9);
+        if body.len() > 255 {
+            frame.push(flags | FLAG_LONG);
+            frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        } else {
+            frame.push(flags);
+            frame.push(body.len() as u8);
+        }
+        frame.extend_from_slice(body);
+        Ok(self.stream.write_all(&frame)?)
+    }
+
+    fn recv_frame(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let mut flags = [0u8; 1];
+        self.stream.read_exact(&mut flags)?;
+        let size = if flags[0] & FLAG_LONG != 0 {
+            let mut size = [0u8; 8];
+            self.stream.read_exact(&mut size)?;
+            u64::from_be_bytes(size)
+        } else {
+            let mut size = [0u8; 1];
+            self.stream.read_exact(&mut size)?;
+            size[0] as u64
+        };
+        //The size comes from the publisher, never allocate more than a block
+        if size > MAXIMUM_FRAME_SIZE {
+            return Err(Error::ZMQProtocol(format!("Frame of {} bytes is too large", size)));
+        }
+        let mut body = vec![0u8; size as usize];
+        self.stream.read_exact(&mut body)?;
+        Ok((flags[0], body))
+    }
+
+    //Blocks until the next multipart message arrives, skipping any commands.
+    pub fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut message = Vec::new();
+        loop {
+            let (flags, body) = self.recv_frame()?;
+            if flags & FLAG_COMMAND != 0 {continue;}
+            message.push(body);
+            if flags & FLAG_MORE == 0 {return Ok(message);}
+            if message.len() >= MAXIMUM_FRAMES {
+                return Err(Error::ZMQProtocol(format!("Message has more than {} frames", MAXIMUM_FRAMES)));
+            }
+        }
+    }
+}
+
+//Keeps a subscription to the topic alive on its own thread, reconnecting on
+//failure, and hands every message published under the topic to the handler.
+pub fn subscribe<F: FnMut(Vec<Vec<u8>>) + Send + 'static>(endpoint: String, topic: &'static str, mut handler: F) {
+    std::thread::spawn(move|| loop {
+        match Subscriber::connect(&endpoint, topic) {
+            Ok(mut subscriber) => {
+                println!("[INFO] Subscribed to {} on {}", topic, endpoint);
+                loop {
+                    match subscriber.recv() {
+                        Ok(message) if message.first().map(|t| t.as_slice()) == Some(topic.as_bytes()) => handler(message),
+                        Ok(_) => continue,
+                        Err(error) => {
+                            println!("[ERROR] Lost ZMQ subscription to {}: {}", endpoint, error);
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(error) => println!("[ERROR] Could not subscribe to {}: {}", endpoint, error)
+        }
+        std::thread::sleep(RECONNECT_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn write_frame(stream: &mut TcpStream, flags: u8, size: u64, body: &[u8]) {
+        match size > 255 {
+            true => {
+                stream.write_all(&[flags | FLAG_LONG]).unwrap();
+                stream.write_all(&size.to_be_bytes()).unwrap();
+            },
+            false => stream.write_all(&[flags, size as u8]).unwrap()
+        }
+        stream.write_all(body).unwrap();
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[0] & FLAG_LONG, 0);
+        let mut body = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut body).unwrap();
+        (header[0], body)
+    }
+
+    //Stands in for Bitcoin Core's PUB socket: checks the handshake and the
+    //subscription and then sends the frames. Returns the endpoint.
+    fn publisher(topic: &'static str, frames: Vec<(u8, u64, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 64];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(&greeting[12..16], b"NULL");
+            greeting[32] = 1;
+            stream.write_all(&greeting).unwrap();
+            let (flags, ready) = read_frame(&mut stream);
+            assert_eq!(flags, FLAG_COMMAND);
+            assert!(ready.starts_with(b"\x05READY\x0bSocket-Type\x00\x00\x00\x03SUB"));
+            let ready = b"\x05READY\x0bSocket-Type\x00\x00\x00\x03PUB";
+            write_frame(&mut stream, FLAG_COMMAND, ready.len() as u64, ready);
+            let (_, subscription) = read_frame(&mut stream);
+            assert_eq!(subscription, [&[0x01], topic.as_bytes()].concat());
+            for (flags, size, body) in frames {
+                write_frame(&mut stream, flags, size, &body);
+            }
+        });
+        endpoint
+    }
+
+    #[test]
+    fn receive_messages() {
+        let block = vec![7u8; 1000];
+        let endpoint = publisher("rawblock", vec![
+            (FLAG_COMMAND, 5, b"\x04PING".to_vec()),
+            (FLAG_MORE, 8, b"rawblock".to_vec()),
+            (FLAG_MORE, block.len() as u64, block.clone()),
+            (0, 4, 1u32.to_le_bytes().to_vec()),
+            (FLAG_MORE, 8, b"rawblock".to_vec()),
+            (0, 0, vec![]),
+        ]);
+        let mut subscriber = Subscriber::connect(&endpoint, "rawblock").unwrap();
+        assert_eq!(subscriber.recv().unwrap(), vec![b"rawblock".to_vec(), block, 1u32.to_le_bytes().to_vec()]);
+        assert_eq!(subscriber.recv().unwrap(), vec![b"rawblock".to_vec(), vec![]]);
+        assert!(subscriber.recv().is_err());
+    }
+
+    //A publisher announcing a huge frame is dropped before anything is allocated.
+    #[test]
+    fn frame_too_large() {
+        let endpoint = publisher("rawtx", vec![(0, u64::MAX, vec![])]);
+        let mut subscriber = Subscriber::connect(&endpoint, "rawtx").unwrap();
+        assert!(matches!(subscriber.recv(), Err(Error::ZMQProtocol(_))));
+    }
+
+    #[test]
+    fn too_many_frames() {
+        let endpoint = publisher("rawtx", vec![(FLAG_MORE, 1, vec![0]); MAXIMUM_FRAMES + 1]);
+        let mut subscriber = Subscriber::connect(&endpoint, "rawtx").unwrap();
+        assert!(matches!(subscriber.recv(), Err(Error::ZMQProtocol(_))));
+    }
+
+    #[test]
+    fn unsupported_endpoint() {
+        assert!(Subscriber::connect("ipc:///tmp/bitcoind", "rawtx").is_err());
+    }
+}